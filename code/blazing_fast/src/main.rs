@@ -1,10 +1,10 @@
-use std::fs::File;
 use std::hint::black_box;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::thread;
-use std::thread::available_parallelism;
-use rustc_hash::FxHashMap;
+use brc::cli::{SourceArgs, ThreadArgs};
+use brc::input::Input;
+use clap::Parser;
 
 #[inline(always)]
 fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
@@ -15,35 +15,49 @@ fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
     i
 }
 
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    threads: ThreadArgs,
+}
+
 fn chunk_indices(memory_map: &[u8], num_cpus: usize) -> Vec<usize> {
     // Split the memory map into chunks of roughly equal size
     let chunk_size = memory_map.len() / num_cpus;
 
     // BUT - chunks are probably not aligned to record boundaries/lines. So for each chunk, we'll
-    // need to expand to the next newline character.
+    // need to expand to just past the next newline character.
     let mut chunk_indices = vec![0];
     let mut counter = chunk_size;
     for _ in 1 .. num_cpus {
+        // More threads than the file has room for: the rest get nothing to do.
+        if counter >= memory_map.len() {
+            chunk_indices.push(memory_map.len());
+            continue;
+        }
         let chunk_starts_at = find_next(
-            &memory_map,
+            memory_map,
             counter,
             '\n'
-        );
+        ) + 1;
         chunk_indices.push(chunk_starts_at);
-        counter = chunk_starts_at + chunk_size + 1;
+        counter = chunk_starts_at + chunk_size;
     }
     chunk_indices
 }
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     let start = std::time::Instant::now();
 
     // Count available CPUs
-    let num_cpus = available_parallelism()?.get();
+    let num_cpus = args.threads.workers(0);
 
     // Memory map the file
-    let file = File::open("../data_builder/measurements_1b.txt")?;
-    let memory_map = unsafe { memmap::Mmap::map(&file)? };
+    let memory_map = Input::open(args.source.path("../data_builder/measurements_1b.txt"))?;
     let chunk_indices = chunk_indices(&memory_map, num_cpus);
 
     // Scoped threads
@@ -60,18 +74,20 @@ fn main() -> anyhow::Result<()> {
             };
             let readings = &readings;
 
+            let threads = &args.threads;
             scope.spawn(move || {
+                threads.pin(cpu);
                 let mut index = chunk_start;
                 while index < chunk_end {
                     let start = index; // Where did we start?
 
                     // Find the first string
-                    let i = find_next(&memory_map, start, ';');
+                    let i = find_next(memory_map, start, ';');
                     black_box(&memory_map[start .. i]);
 
                     // Find the second string
                     let start = i + 1;
-                    let i = find_next(&memory_map, start, '\n');
+                    let i = find_next(memory_map, start, '\n');
                     black_box(&memory_map[start..i]);
                     readings.fetch_add(1, Relaxed);
 
@@ -80,6 +96,7 @@ fn main() -> anyhow::Result<()> {
             });
         }
     });
+    memory_map.verify()?;
 
     println!("Elapsed: {:.4} seconds", start.elapsed().as_secs_f32());
     println!("Readings: {}", readings.load(Relaxed));
@@ -1,8 +1,10 @@
-use std::fs::File;
 use std::hash::Hasher;
 use std::hint::black_box;
 use std::thread;
-use std::thread::available_parallelism;
+use brc::ascii::ascii_slice_to_i32;
+use brc::cli::{SourceArgs, ThreadArgs};
+use brc::input::Input;
+use clap::Parser;
 use rustc_hash::{FxHashSet, FxHasher};
 
 #[inline(always)]
@@ -14,62 +16,49 @@ fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
     i
 }
 
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    threads: ThreadArgs,
+}
+
 fn chunk_indices(memory_map: &[u8], num_cpus: usize) -> Vec<usize> {
     // Split the memory map into chunks of roughly equal size
     let chunk_size = memory_map.len() / num_cpus;
 
     // BUT - chunks are probably not aligned to record boundaries/lines. So for each chunk, we'll
-    // need to expand to the next newline character.
+    // need to expand to just past the next newline character.
     let mut chunk_indices = vec![0];
     let mut counter = chunk_size;
     for _ in 1 .. num_cpus {
+        // More threads than the file has room for: the rest get nothing to do.
+        if counter >= memory_map.len() {
+            chunk_indices.push(memory_map.len());
+            continue;
+        }
         let chunk_starts_at = find_next(
-            &memory_map,
+            memory_map,
             counter,
             '\n'
-        );
+        ) + 1;
         chunk_indices.push(chunk_starts_at);
-        counter = chunk_starts_at + chunk_size + 1;
+        counter = chunk_starts_at + chunk_size;
     }
     chunk_indices
 }
 
-fn ascii_slice_to_i32(buffer: &[u8]) -> i32 {
-    let size = buffer.len();
-    let mut negative_multiplier = 1;
-    let mut accumulator = 0;
-    let mut positional_mul = 10_i32.pow(size as u32 - 2);
-    for i in 0 .. size {
-        match buffer[i] {
-            b'-' => {
-                negative_multiplier = -1;
-                positional_mul /= 10;
-            }
-            b'.' => {
-                // Do nothing
-            }
-            48 ..= 57 => {
-                // Digits
-                let digit = buffer[i] as i32 - 48;
-                accumulator += digit * positional_mul;
-                positional_mul /= 10;
-            }
-            _ => panic!("Unhandled ASCII numerical symbol: {}", buffer[i]),
-        }
-    }
-    accumulator *= negative_multiplier;
-    accumulator
-}
-
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     let start = std::time::Instant::now();
 
     // Count available CPUs
-    let num_cpus = available_parallelism()?.get();
+    let num_cpus = args.threads.workers(0);
 
     // Memory map the file
-    let file = File::open("../data_builder/measurements.txt")?;
-    let memory_map = unsafe { memmap::Mmap::map(&file)? };
+    let memory_map = Input::open(args.source.path("../data_builder/measurements.txt"))?;
     let chunk_indices = chunk_indices(&memory_map, num_cpus);
 
     // Scoped threads
@@ -84,7 +73,9 @@ fn main() -> anyhow::Result<()> {
                 chunk_indices[cpu + 1]
             };
 
+            let threads = &args.threads;
             scope.spawn(move || {
+                threads.pin(cpu);
                 let mut index = chunk_start;
                 let mut name_set = FxHashSet::default();
                 let mut counter = 0;
@@ -92,7 +83,7 @@ fn main() -> anyhow::Result<()> {
                     let start = index; // Where did we start?
 
                     // Find the first string
-                    let i = find_next(&memory_map, start, ';');
+                    let i = find_next(memory_map, start, ';');
                     let station_slice = &memory_map[start .. i];
 
                     // Hash the station name
@@ -104,8 +95,8 @@ fn main() -> anyhow::Result<()> {
 
                     // Find the second string
                     let start = i + 1;
-                    let i = find_next(&memory_map, start, '\n');
-                    let temperature = ascii_slice_to_i32(&memory_map[start..i]);
+                    let i = find_next(memory_map, start, '\n');
+                    black_box(ascii_slice_to_i32(&memory_map[start..i]).ok());
 
                     index = i + 1;
                 }
@@ -114,6 +105,7 @@ fn main() -> anyhow::Result<()> {
             });
         }
     });
+    memory_map.verify()?;
 
     println!("Elapsed: {:.4} seconds", start.elapsed().as_secs_f32());
 
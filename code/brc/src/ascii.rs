@@ -0,0 +1,273 @@
+//! Parsing temperatures straight out of ASCII bytes, as fixed-point numbers.
+
+use std::fmt;
+use std::str::FromStr;
+use crate::error::ParseErrorKind;
+
+/// How many digits after the decimal point a fixed-point value carries. The challenge uses
+/// tenths; a scale of 2 stores hundredths, 0 stores whole numbers, and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    digits: u32,
+}
+
+impl Scale {
+    /// One decimal place, as in the challenge data.
+    pub const TENTHS: Scale = Scale { digits: 1 };
+
+    /// The most fractional digits we support. Any more and an `i32` can't hold much else.
+    pub const MAX_DIGITS: u32 = 6;
+
+    pub fn new(digits: u32) -> Option<Self> {
+        (digits <= Self::MAX_DIGITS).then_some(Scale { digits })
+    }
+
+    pub fn digits(&self) -> u32 {
+        self.digits
+    }
+
+    /// 10 to the power of `digits`: what a value gets multiplied by to become fixed-point.
+    pub fn factor(&self) -> i64 {
+        10_i64.pow(self.digits)
+    }
+
+    /// Parses a value such as `-12.3` at this scale (`-123` for tenths, `-1230` for hundredths).
+    ///
+    /// At the challenge's scale, anything shaped like `-?\d?\d\.\d` takes the branchless fast
+    /// path; everything else falls through to [`parse_general`]. Values with more fractional
+    /// digits than the scale allows are rejected rather than rounded.
+    #[inline(always)]
+    pub fn parse(&self, buffer: &[u8]) -> Result<i32, ParseErrorKind> {
+        if self.digits == 1 && is_fixed_format(buffer) {
+            Ok(parse_fixed_format(buffer))
+        } else {
+            parse_general(buffer, self.digits)
+        }
+    }
+
+    /// Formats a fixed-point value at this scale, without going through a float.
+    pub fn format(&self, value: i64) -> Fixed {
+        Fixed { value, scale: *self }
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self::TENTHS
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .ok()
+            .and_then(Scale::new)
+            .ok_or_else(|| format!("scale must be a number of digits from 0 to {}", Scale::MAX_DIGITS))
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.digits)
+    }
+}
+
+/// A fixed-point value paired with its scale, for printing.
+pub struct Fixed {
+    value: i64,
+    scale: Scale,
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let absolute = self.value.unsigned_abs();
+        let factor = self.scale.factor() as u64;
+        if self.scale.digits == 0 {
+            write!(f, "{sign}{absolute}")
+        } else {
+            let width = self.scale.digits as usize;
+            write!(f, "{sign}{}.{:0width$}", absolute / factor, absolute % factor)
+        }
+    }
+}
+
+/// Parses a temperature such as `-12.3` into tenths of a degree (`-123`).
+#[inline(always)]
+pub fn ascii_slice_to_i32(buffer: &[u8]) -> Result<i32, ParseErrorKind> {
+    Scale::TENTHS.parse(buffer)
+}
+
+/// Does `buffer` look exactly like `-?\d?\d\.\d`?
+#[inline(always)]
+pub fn is_fixed_format(buffer: &[u8]) -> bool {
+    matches!(
+        buffer,
+        [b'0'..=b'9', b'.', b'0'..=b'9']
+            | [b'0'..=b'9', b'0'..=b'9', b'.', b'0'..=b'9']
+            | [b'-', b'0'..=b'9', b'.', b'0'..=b'9']
+            | [b'-', b'0'..=b'9', b'0'..=b'9', b'.', b'0'..=b'9']
+    )
+}
+
+/// Branchless parse of a value already known to match `-?\d?\d\.\d`.
+///
+/// The whole value fits in one little-endian word, so rather than walking it byte by byte we
+/// find the decimal point with a mask, line the digits up with a shift, and let a single
+/// multiply add them together with the right place values.
+#[inline(always)]
+pub fn parse_fixed_format(buffer: &[u8]) -> i32 {
+    debug_assert!(is_fixed_format(buffer));
+    let mut bytes = [0u8; 8];
+    bytes[..buffer.len()].copy_from_slice(buffer);
+    let word = u64::from_le_bytes(bytes);
+
+    // Digits (0x30..=0x39) have bit 4 set, '.' (0x2E) does not. Byte 0 is left out of the mask
+    // because it may be a '-', which also has bit 4 clear.
+    let decimal_position = (!word & 0x1010_1000).trailing_zeros();
+
+    // All ones if the first byte is '-', all zeroes otherwise.
+    let signed = ((!word << 59) as i64) >> 63;
+    let sign_mask = !(signed as u64 & 0xFF);
+
+    // Shift so the decimal point always lands in byte 3, then keep only the digit nibbles:
+    // tens in byte 1, units in byte 2 and tenths in byte 4.
+    let digits = ((word & sign_mask) << (28 - decimal_position)) & 0x0F_000F_0F00;
+
+    // 0x640a0001 is (100 << 24) + (10 << 16) + 1: each digit gets multiplied by its place value
+    // and the three products meet in bits 32 and up.
+    let absolute = (digits.wrapping_mul(0x640a_0001) >> 32) & 0x3FF;
+    ((absolute as i64 ^ signed) - signed) as i32
+}
+
+/// Slow-but-forgiving parser for anything outside the challenge format: an optional sign,
+/// any number of integer digits and up to `fraction_digits` digits after the decimal point
+/// (`7`, `+1.5`, `123.4`, `.5`). The result is scaled up to `fraction_digits` places.
+pub fn parse_general(buffer: &[u8], fraction_digits: u32) -> Result<i32, ParseErrorKind> {
+    let (negative, digits) = match buffer.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, buffer),
+    };
+
+    let mut accumulator: i32 = 0;
+    let mut seen_digit = false;
+    let mut seen_fraction_digits = None;
+    for &byte in digits {
+        match byte {
+            b'0'..=b'9' => {
+                if seen_fraction_digits == Some(fraction_digits) {
+                    return Err(ParseErrorKind::TooManyFractionDigits);
+                }
+                accumulator = accumulator
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add((byte - b'0') as i32))
+                    .ok_or(ParseErrorKind::OutOfRange)?;
+                seen_digit = true;
+                if let Some(seen_fraction_digits) = seen_fraction_digits.as_mut() {
+                    *seen_fraction_digits += 1;
+                }
+            }
+            b'.' if seen_fraction_digits.is_none() => seen_fraction_digits = Some(0),
+            _ => return Err(ParseErrorKind::InvalidByte(byte)),
+        }
+    }
+    if !seen_digit {
+        return Err(ParseErrorKind::NoDigits);
+    }
+
+    // Pad out values with fewer fractional digits ("7", "12." or "1.5" at hundredths).
+    for _ in seen_fraction_digits.unwrap_or(0) .. fraction_digits {
+        accumulator = accumulator.checked_mul(10).ok_or(ParseErrorKind::OutOfRange)?;
+    }
+
+    Ok(if negative { -accumulator } else { accumulator })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenths_to_ascii(tenths: i32) -> String {
+        let sign = if tenths < 0 { "-" } else { "" };
+        format!("{sign}{}.{}", tenths.abs() / 10, tenths.abs() % 10)
+    }
+
+    #[test]
+    fn fixed_format_covers_every_challenge_value() {
+        for tenths in -999..=999 {
+            let text = tenths_to_ascii(tenths);
+            assert!(is_fixed_format(text.as_bytes()), "{text}");
+            assert_eq!(parse_fixed_format(text.as_bytes()), tenths, "{text}");
+            assert_eq!(parse_general(text.as_bytes(), 1), Ok(tenths), "{text}");
+            assert_eq!(Scale::TENTHS.format(tenths as i64).to_string(), text);
+            assert_eq!(ascii_slice_to_i32(text.as_bytes()), Ok(tenths), "{text}");
+        }
+    }
+
+    #[test]
+    fn negative_zero_is_zero() {
+        assert_eq!(ascii_slice_to_i32(b"-0.0"), Ok(0));
+    }
+
+    #[test]
+    fn other_shapes_use_the_general_parser() {
+        for (text, tenths) in [
+            ("7", 70),
+            ("-7", -70),
+            ("+1.5", 15),
+            ("123.4", 1234),
+            ("-100.0", -1000),
+            (".5", 5),
+            ("12.", 120),
+            ("007.5", 75),
+        ] {
+            assert!(!is_fixed_format(text.as_bytes()), "{text}");
+            assert_eq!(ascii_slice_to_i32(text.as_bytes()), Ok(tenths), "{text}");
+        }
+    }
+
+    #[test]
+    fn malformed_values_are_errors() {
+        for (text, kind) in [
+            ("", ParseErrorKind::NoDigits),
+            ("-", ParseErrorKind::NoDigits),
+            (".", ParseErrorKind::NoDigits),
+            ("1.25", ParseErrorKind::TooManyFractionDigits),
+            ("1x.5", ParseErrorKind::InvalidByte(b'x')),
+            ("1.5\r", ParseErrorKind::InvalidByte(b'\r')),
+            ("1.2.3", ParseErrorKind::InvalidByte(b'.')),
+            ("99999999999", ParseErrorKind::OutOfRange),
+        ] {
+            assert_eq!(ascii_slice_to_i32(text.as_bytes()), Err(kind), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn other_scales() {
+        let hundredths = Scale::new(2).unwrap();
+        let whole = Scale::new(0).unwrap();
+        for (scale, text, expected) in [
+            (hundredths, "1.25", Ok(125)),
+            (hundredths, "-1.2", Ok(-120)),
+            (hundredths, "7", Ok(700)),
+            (hundredths, "1.255", Err(ParseErrorKind::TooManyFractionDigits)),
+            (whole, "12", Ok(12)),
+            (whole, "-12.", Ok(-12)),
+            (whole, "1.5", Err(ParseErrorKind::TooManyFractionDigits)),
+        ] {
+            assert_eq!(scale.parse(text.as_bytes()), expected, "{text} at {scale}");
+        }
+    }
+
+    #[test]
+    fn formatting_matches_the_scale() {
+        assert_eq!(Scale::new(2).unwrap().format(-5).to_string(), "-0.05");
+        assert_eq!(Scale::new(3).unwrap().format(12_345).to_string(), "12.345");
+        assert_eq!(Scale::new(0).unwrap().format(-42).to_string(), "-42");
+        assert_eq!(Scale::TENTHS.format(-999).to_string(), "-99.9");
+        assert_eq!(Scale::TENTHS.format(0).to_string(), "0.0");
+    }
+}
@@ -0,0 +1,528 @@
+//! Reading the input with explicit reads into reusable buffers, instead of mapping it.
+//!
+//! A reader thread fills a small pool of buffers a segment at a time, with `pread` or, on
+//! Linux, `io_uring`, or with plain reads for a stream that can't be read any other way, and
+//! hands them on as record-aligned [`Block`]s. A block's buffer goes
+//! back to the pool when the block is dropped, so however big the input is, only the pool is
+//! ever resident.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use clap::ValueEnum;
+use crate::compressed::{decompressed, Compression, FrameDecoder};
+use crate::input::{Piece, Source, STDIN};
+
+/// How the input gets from the disk into memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Map the file and let page faults read it in.
+    #[default]
+    Mmap,
+    /// Read blocks into reusable buffers with `pread`.
+    Pread,
+    /// Read blocks into reusable buffers with `io_uring`, several at once (Linux only).
+    Uring,
+}
+
+/// How much to read at a time, unless told otherwise.
+pub const DEFAULT_BLOCK_SIZE: usize = 16 << 20;
+
+/// Buffers are made of these, so they're aligned well enough for `O_DIRECT`.
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct Page([u8; PAGE]);
+
+const PAGE: usize = 4096;
+
+/// Room at the front of each buffer for the end of the last record in the previous segment, so
+/// a block can be handed on without copying the segment. Records are never this long.
+const HEADROOM: usize = 64 * 1024;
+
+/// A page-aligned buffer: [`HEADROOM`] bytes, then a segment of the file.
+struct Buffer {
+    pages: Vec<Page>,
+}
+
+impl Buffer {
+    fn new(segment_size: usize) -> Self {
+        Buffer { pages: vec![Page([0; PAGE]); (HEADROOM + segment_size) / PAGE] }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: `Page` is plain bytes with no padding, so the pages are one run of bytes.
+        unsafe { std::slice::from_raw_parts(self.pages.as_ptr().cast(), self.pages.len() * PAGE) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above.
+        unsafe { std::slice::from_raw_parts_mut(self.pages.as_mut_ptr().cast(), self.pages.len() * PAGE) }
+    }
+
+    /// Where the segment goes.
+    fn segment_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes_mut()[HEADROOM..]
+    }
+}
+
+/// How to read blocks.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockOptions {
+    /// [`Backend::Pread`] or [`Backend::Uring`].
+    pub backend: Backend,
+    /// How much to read at a time. Rounded up to a whole number of pages.
+    pub block_size: usize,
+    /// How many reads `io_uring` keeps in flight.
+    pub queue_depth: usize,
+    /// Open the file with `O_DIRECT`, bypassing the page cache, where that's supported.
+    pub direct: bool,
+    /// How many threads may decompress the frames of a compressed file at once.
+    pub threads: usize,
+}
+
+/// Reads the input a block at a time on a background thread. Each block ends just after a
+/// newline, like a [`crate::input::Window`]. A file is locked and checked the same way as
+/// [`crate::input::Input`]; standard input, pipes and FIFOs are read front to back instead.
+/// Compressed input is decompressed on the way; see [`crate::compressed`].
+pub struct Blocks {
+    source: Option<Source>,
+    blocks: Receiver<io::Result<Block>>,
+}
+
+impl Blocks {
+    /// Opens `path`, or standard input if it's [`STDIN`].
+    pub fn open(path: impl AsRef<Path>, options: BlockOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path == Path::new(STDIN) {
+            return Ok(Self::stream(decompressed(io::stdin())?, options.block_size));
+        }
+        if !std::fs::metadata(path)?.is_file() {
+            return Ok(Self::stream(decompressed(File::open(path)?)?, options.block_size));
+        }
+        let source = Source::open(path)?;
+        if let Some(compression) = Compression::of_file(path)? {
+            let data = Arc::new(source.read(0, source.len as usize, true)?);
+            let reader: Box<dyn Read + Send> = match compression.frames(&data) {
+                Some(frames) if frames.len() > 1 && options.threads > 1 => {
+                    Box::new(FrameDecoder::new(data, compression, frames, options.threads))
+                }
+                _ => compression.decoder(BufReader::new(File::open(path)?))?,
+            };
+            let blocks = Self::stream(reader, options.block_size).blocks;
+            return Ok(Blocks { source: Some(source), blocks });
+        }
+        let file = open_for_reading(path, options.direct)?;
+        let len = source.len;
+        let segment_size = segment_size(options.block_size);
+        let queue_depth = options.queue_depth.max(1);
+
+        // Enough buffers for every read in flight, the block being worked on and the one queued
+        // behind it.
+        let (buffers, backend) = match options.backend {
+            Backend::Uring => (queue_depth + 2, Backend::Uring),
+            _ => (3, Backend::Pread),
+        };
+        let blocks = spawn(buffers, segment_size, move |pool, assembler| match backend {
+            Backend::Uring => uring::read(&file, len, segment_size, queue_depth, pool, assembler).or_else(|e| match e {
+                Fallback::Unsupported(e) => {
+                    eprintln!("io_uring isn't available ({e}), reading with pread instead");
+                    pread(&file, len, pool, assembler)
+                }
+                Fallback::Failed(e) => Err(e),
+            }),
+            _ => pread(&file, len, pool, assembler),
+        });
+        Ok(Blocks { source: Some(source), blocks })
+    }
+
+    /// Reads `reader` front to back, `block_size` bytes at a time.
+    pub fn stream(reader: impl Read + Send + 'static, block_size: usize) -> Self {
+        let blocks = spawn(3, segment_size(block_size), move |pool, assembler| read_stream(reader, pool, assembler));
+        Blocks { source: None, blocks }
+    }
+
+    /// See [`crate::input::Input::verify`]. There's nothing to check for a stream.
+    pub fn verify(&self) -> io::Result<()> {
+        self.source.as_ref().map_or(Ok(()), Source::verify)
+    }
+}
+
+fn segment_size(block_size: usize) -> usize {
+    block_size.max(1).next_multiple_of(PAGE)
+}
+
+/// Starts a thread running `read` with a pool of `buffers` buffers, and returns where its
+/// blocks come out.
+fn spawn(
+    buffers: usize,
+    segment_size: usize,
+    read: impl FnOnce(&Receiver<Buffer>, &mut Assembler) -> io::Result<()> + Send + 'static,
+) -> Receiver<io::Result<Block>> {
+    let (recycle, pool) = mpsc::channel();
+    for _ in 0..buffers {
+        recycle.send(Buffer::new(segment_size)).unwrap();
+    }
+    let (sender, blocks) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let mut assembler = Assembler { carry: Vec::new(), offset: 0, sender, recycle };
+        if let Err(e) = read(&pool, &mut assembler) {
+            let _ = assembler.sender.send(Err(e));
+        }
+    });
+    blocks
+}
+
+impl Iterator for Blocks {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.blocks.recv().ok()
+    }
+}
+
+/// One record-aligned piece of the input; see [`Blocks`].
+pub struct Block {
+    buffer: Option<Buffer>,
+    start: usize,
+    end: usize,
+    offset: u64,
+    recycle: Sender<Buffer>,
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer.as_ref().unwrap().bytes()[self.start..self.end]
+    }
+}
+
+impl Piece for Block {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        // If the reader has finished, nobody wants it back.
+        let _ = self.recycle.send(self.buffer.take().unwrap());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_for_reading(path: &Path, direct: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    if direct {
+        match File::options().read(true).custom_flags(libc::O_DIRECT).open(path) {
+            Ok(file) => return Ok(file),
+            // tmpfs and friends don't do O_DIRECT.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                eprintln!("{} can't be opened with O_DIRECT, reading through the page cache instead", path.display());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    File::open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_for_reading(path: &Path, direct: bool) -> io::Result<File> {
+    if direct {
+        eprintln!("O_DIRECT is only supported on Linux, reading through the page cache instead");
+    }
+    File::open(path)
+}
+
+/// Reads from `offset` until `buffer` is full or the file ends, returning how much was read.
+fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    use std::os::unix::fs::FileExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileExt;
+
+    let mut filled = 0;
+    while filled < buffer.len() {
+        #[cfg(unix)]
+        let n = file.read_at(&mut buffer[filled..], offset + filled as u64);
+        #[cfg(windows)]
+        let n = file.seek_read(&mut buffer[filled..], offset + filled as u64);
+        match n {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn pread(file: &File, len: u64, pool: &Receiver<Buffer>, assembler: &mut Assembler) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < len {
+        // No buffers coming back means nobody wants any more blocks.
+        let Ok(mut buffer) = pool.recv() else { return Ok(()) };
+        let n = read_at(file, buffer.segment_mut(), offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        offset += n as u64;
+        if !assembler.push(buffer, n, offset >= len)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_stream(mut reader: impl Read, pool: &Receiver<Buffer>, assembler: &mut Assembler) -> io::Result<()> {
+    loop {
+        let Ok(mut buffer) = pool.recv() else { return Ok(()) };
+        // Fill the whole buffer, however little each read returns, so blocks stay big.
+        let segment = buffer.segment_mut();
+        let mut n = 0;
+        while n < segment.len() {
+            match reader.read(&mut segment[n..]) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let last = n < segment.len();
+        if !assembler.push(buffer, n, last)? || last {
+            return Ok(());
+        }
+    }
+}
+
+/// Why `io_uring` didn't read the file: either we can't use it at all, and pread should be
+/// used instead, or reading went wrong.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum Fallback {
+    Unsupported(io::Error),
+    Failed(io::Error),
+}
+
+impl From<io::Error> for Fallback {
+    fn from(e: io::Error) -> Self {
+        Fallback::Failed(e)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod uring {
+    use super::*;
+    use std::os::fd::AsRawFd;
+    use io_uring::{opcode, types, IoUring};
+
+    /// The reads that have been submitted, oldest first, and how each turned out once it has.
+    struct InFlight {
+        ring: IoUring,
+        reads: VecDeque<(u64, Buffer, Option<i32>)>,
+    }
+
+    impl InFlight {
+        /// Waits for at least one more read to complete.
+        fn wait(&mut self) -> io::Result<()> {
+            self.ring.submit_and_wait(1)?;
+            for completion in self.ring.completion() {
+                let offset = completion.user_data();
+                if let Some(read) = self.reads.iter_mut().find(|read| read.0 == offset) {
+                    read.2 = Some(completion.result());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            // The kernel may still be writing into the buffers, so they can't be freed until
+            // it's done with them.
+            while self.reads.iter().any(|read| read.2.is_none()) {
+                if self.wait().is_err() {
+                    // Leaking them is the only safe option left.
+                    std::mem::forget(std::mem::take(&mut self.reads));
+                    return;
+                }
+            }
+        }
+    }
+
+    pub(super) fn read(
+        file: &File,
+        len: u64,
+        segment_size: usize,
+        queue_depth: usize,
+        pool: &Receiver<Buffer>,
+        assembler: &mut Assembler,
+    ) -> Result<(), Fallback> {
+        let ring = IoUring::new(queue_depth.next_power_of_two() as u32).map_err(Fallback::Unsupported)?;
+        let mut in_flight = InFlight { ring, reads: VecDeque::new() };
+        let mut next = 0;
+        loop {
+            // Keep the queue full, as far as there are buffers to read into.
+            while in_flight.reads.len() < queue_depth && next < len {
+                let buffer = if in_flight.reads.is_empty() { pool.recv().ok() } else { pool.try_recv().ok() };
+                let Some(mut buffer) = buffer else { break };
+                let segment = buffer.segment_mut();
+                let read = opcode::Read::new(types::Fd(file.as_raw_fd()), segment.as_mut_ptr(), segment.len() as u32)
+                    .offset(next)
+                    .build()
+                    .user_data(next);
+                // SAFETY: the buffer stays in `in_flight` until the read completes, and
+                // `InFlight`'s drop makes sure of that on the way out too.
+                unsafe { in_flight.ring.submission().push(&read) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+                in_flight.reads.push_back((next, buffer, None));
+                next += segment_size as u64;
+            }
+            if in_flight.reads.is_empty() {
+                // Either the file is done, or nobody wants any more blocks.
+                return Ok(());
+            }
+
+            // Blocks have to go out in order, so wait for the oldest read.
+            while in_flight.reads[0].2.is_none() {
+                in_flight.wait()?;
+            }
+            let (offset, mut buffer, result) = in_flight.reads.pop_front().unwrap();
+            let result = result.unwrap();
+            if result < 0 {
+                let e = io::Error::from_raw_os_error(-result);
+                // The first read failing probably means this file can't be read this way.
+                return Err(if offset == 0 { Fallback::Unsupported(e) } else { Fallback::Failed(e) });
+            }
+            // A short read before the end of the file: pick up the rest the slow way.
+            let mut n = result as usize;
+            let expected = u64::min(segment_size as u64, len - offset) as usize;
+            if n < expected {
+                n += read_at(file, &mut buffer.segment_mut()[n..expected], offset + n as u64)?;
+                if n < expected {
+                    return Err(Fallback::Failed(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+            if !assembler.push(buffer, n, offset + n as u64 >= len)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod uring {
+    use super::*;
+
+    pub(super) fn read(_: &File, _: u64, _: usize, _: usize, _: &Receiver<Buffer>, _: &mut Assembler) -> Result<(), Fallback> {
+        Err(Fallback::Unsupported(io::Error::other("io_uring needs Linux")))
+    }
+}
+
+/// Turns segments of the file into record-aligned blocks.
+struct Assembler {
+    /// The start of a record that ran off the end of the last segment.
+    carry: Vec<u8>,
+    /// Where `carry` starts in the file.
+    offset: u64,
+    sender: SyncSender<io::Result<Block>>,
+    recycle: Sender<Buffer>,
+}
+
+impl Assembler {
+    /// Sends on the complete records among the carry and the `n` bytes just read into `buffer`.
+    /// Returns false if nobody's listening any more.
+    fn push(&mut self, mut buffer: Buffer, n: usize, last: bool) -> io::Result<bool> {
+        let segment = &buffer.segment_mut()[..n];
+        let split = if last {
+            n
+        } else {
+            match segment.iter().rposition(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None => 0,
+            }
+        };
+        let carry_len = self.carry.len();
+        let carry_after = if split == 0 { carry_len + n } else { n - split };
+        if carry_after > HEADROOM {
+            return Err(io::Error::other(format!(
+                "a record at byte {} is longer than {} bytes",
+                self.offset, HEADROOM
+            )));
+        }
+        if split == 0 && !last {
+            // Not even one whole record: hang on to all of it.
+            self.carry.extend_from_slice(segment);
+            let _ = self.recycle.send(buffer);
+            return Ok(true);
+        }
+        let tail = segment[split..].to_vec();
+        let start = HEADROOM - carry_len;
+        buffer.bytes_mut()[start..HEADROOM].copy_from_slice(&self.carry);
+        let block = Block {
+            buffer: Some(buffer),
+            start,
+            end: HEADROOM + split,
+            offset: self.offset,
+            recycle: self.recycle.clone(),
+        };
+        self.offset += (carry_len + split) as u64;
+        self.carry = tail;
+        if block.is_empty() {
+            return Ok(true);
+        }
+        Ok(self.sender.send(Ok(block)).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn blocks_end_on_records_and_cover_the_file() {
+        let contents: Vec<u8> = (0..5000).flat_map(|i| format!("Station {i};{}.{}\n", i % 100, i % 10).into_bytes()).collect();
+        let path = std::env::temp_dir().join(format!("brc-blocks-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(&contents[..contents.len() - 1]).unwrap();
+        let contents = &contents[..contents.len() - 1];
+        for backend in [Backend::Pread, Backend::Uring] {
+            for block_size in [1, 4096, 10_000, 1 << 20] {
+                let options = BlockOptions { backend, block_size, queue_depth: 3, direct: false, threads: 1 };
+                let mut next = 0;
+                let mut blocks = Blocks::open(&path, options).unwrap();
+                for block in blocks.by_ref() {
+                    let block = block.unwrap();
+                    assert_eq!(block.offset(), next);
+                    assert_eq!(&*block, &contents[next as usize..next as usize + block.len()]);
+                    next += block.len() as u64;
+                    assert!(next == contents.len() as u64 || block.ends_with(b"\n"));
+                }
+                assert_eq!(next, contents.len() as u64, "{backend:?}, block size {block_size}");
+                assert!(blocks.verify().is_ok());
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn streams_keep_the_record_that_ends_a_block() {
+        // The first buffer ends part way into a record that fills the whole of the second, so
+        // when the stream ends right there, the carry has to come out as a block on its own.
+        let mut contents = b"Oslo;1.0\n".repeat(PAGE / 9 + 1);
+        contents.truncate(PAGE);
+        contents.extend(std::iter::repeat_n(b'x', PAGE));
+        for end in [contents.len(), contents.len() - 1] {
+            let blocks: Vec<Block> = Blocks::stream(io::Cursor::new(contents[..end].to_vec()), PAGE)
+                .map(Result::unwrap)
+                .collect();
+            let joined: Vec<u8> = blocks.iter().flat_map(|block| block.iter().copied()).collect();
+            assert_eq!(joined, &contents[..end]);
+            assert_eq!(blocks.last().unwrap().offset(), (PAGE - 1) as u64);
+        }
+    }
+}
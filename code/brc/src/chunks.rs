@@ -0,0 +1,225 @@
+//! Handing out record-aligned pieces of the input to worker threads.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use clap::ValueEnum;
+use crate::error::{ErrorPolicy, ErrorReport, ParseError};
+
+/// How the input is shared between workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Scheduler {
+    /// Many small chunks, pulled from a shared queue by whichever worker is free.
+    #[default]
+    Stealing,
+    /// One contiguous chunk per worker. A slow worker holds everyone else up at the end, but
+    /// it's handy for comparison.
+    Static,
+}
+
+/// Splits `buffer` into chunks of at least `chunk_size` bytes, each extended to just past the
+/// next newline so that no record straddles two chunks.
+pub fn split(buffer: &[u8], chunk_size: usize) -> Vec<Range<usize>> {
+    let chunk_size = chunk_size.max(1);
+    let mut chunks = Vec::with_capacity(buffer.len() / chunk_size + 1);
+    let mut start = 0;
+    while start < buffer.len() {
+        let end = buffer[usize::min(start + chunk_size - 1, buffer.len())..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(buffer.len(), |i| start + chunk_size + i);
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
+}
+
+/// The chunks of the input, and a shared cursor into them.
+///
+/// With [`Scheduler::Stealing`] every worker takes the next unclaimed chunk when it finishes
+/// one, so fast workers end up doing more of them. With [`Scheduler::Static`] there's one chunk
+/// per worker and worker `n` always gets chunk `n`.
+pub struct ChunkQueue {
+    chunks: Vec<Range<usize>>,
+    next: AtomicUsize,
+    scheduler: Scheduler,
+}
+
+impl ChunkQueue {
+    pub fn new(buffer: &[u8], workers: usize, scheduler: Scheduler, chunk_size: usize) -> Self {
+        let chunk_size = match scheduler {
+            Scheduler::Stealing => chunk_size,
+            Scheduler::Static => buffer.len().div_ceil(workers.max(1)),
+        };
+        Self {
+            chunks: split(buffer, chunk_size),
+            next: AtomicUsize::new(0),
+            scheduler,
+        }
+    }
+
+    /// How many chunks the input was split into.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The byte range of chunk `index`.
+    pub fn chunk(&self, index: usize) -> Range<usize> {
+        self.chunks[index].clone()
+    }
+
+    /// How many chunks have been handed out so far.
+    pub fn claimed(&self) -> usize {
+        match self.scheduler {
+            Scheduler::Stealing => usize::min(self.next.load(Ordering::Relaxed), self.chunks.len()),
+            Scheduler::Static => self.chunks.len(),
+        }
+    }
+
+    /// The chunks for worker number `worker`, as `(index, byte range)` pairs.
+    pub fn worker(&self, worker: usize) -> WorkerChunks<'_> {
+        WorkerChunks { queue: self, worker, done: false }
+    }
+
+    /// Stop handing out chunks. Chunks already handed out still get finished, so every chunk
+    /// before the one that failed is read, and the earliest error in the input is still found.
+    pub fn cancel(&self) {
+        self.next.fetch_max(self.chunks.len(), Ordering::Relaxed);
+    }
+
+    /// Turns a line number counted from the start of chunk `index` into one counted from the
+    /// start of the input. Every chunk before it ends in a newline, so we only need to count
+    /// those; this only happens once, on the way out, so it doesn't need to be fast.
+    pub fn locate(&self, buffer: &[u8], index: usize, mut error: ParseError) -> ParseError {
+        let start = self.chunks[index].start;
+        error.line += buffer[..start].iter().filter(|&&b| b == b'\n').count() as u64;
+        error
+    }
+}
+
+/// The chunks one worker should read; see [`ChunkQueue::worker`].
+pub struct WorkerChunks<'a> {
+    queue: &'a ChunkQueue,
+    worker: usize,
+    done: bool,
+}
+
+impl Iterator for WorkerChunks<'_> {
+    type Item = (usize, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let index = match self.queue.scheduler {
+            Scheduler::Stealing => self.queue.next.fetch_add(1, Ordering::Relaxed),
+            Scheduler::Static => {
+                self.done = true;
+                self.worker
+            }
+        };
+        let chunk = self.queue.chunks.get(index)?;
+        Some((index, chunk.clone()))
+    }
+}
+
+/// What a worker found wrong in one chunk, and how many lines the chunk had.
+pub struct ChunkReport {
+    pub index: usize,
+    pub errors: ErrorReport,
+    pub lines: u64,
+}
+
+/// Gathers the per-chunk reports from all the workers, in whatever order they finish, and puts
+/// them back into input order.
+pub struct ChunkErrors {
+    policy: ErrorPolicy,
+    reports: Vec<ChunkReport>,
+    first_error: Option<ParseError>,
+}
+
+impl ChunkErrors {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            reports: Vec::new(),
+            first_error: None,
+        }
+    }
+
+    /// Adds the reports from a worker that got to the end.
+    pub fn add(&mut self, reports: Vec<ChunkReport>) {
+        self.reports.extend(reports);
+    }
+
+    /// Adds the error that stopped a worker, which should already have been through
+    /// [`ChunkQueue::locate`]. Only the earliest one in the input is kept.
+    pub fn fail(&mut self, error: ParseError) {
+        if self.first_error.as_ref().is_none_or(|first| error.offset < first.offset) {
+            self.first_error = Some(error);
+        }
+    }
+
+    /// How many lines the finished chunks had between them.
+    pub fn lines(&self) -> u64 {
+        self.reports.iter().map(|report| report.lines).sum()
+    }
+
+    /// The combined report, or the earliest error if any worker stopped on one.
+    pub fn finish(mut self) -> Result<ErrorReport, ParseError> {
+        if let Some(error) = self.first_error {
+            return Err(error);
+        }
+        self.reports.sort_unstable_by_key(|report| report.index);
+        let mut errors = ErrorReport::new(self.policy);
+        let mut lines_before = 0;
+        for report in self.reports {
+            errors.merge(report.errors, lines_before);
+            lines_before += report.lines;
+        }
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_end_after_a_newline_and_cover_everything() {
+        let input = b"a;1\nbb;2\nccc;3\nd;4";
+        for chunk_size in 1..=input.len() + 1 {
+            let chunks = split(input, chunk_size);
+            assert_eq!(chunks.first().map(|c| c.start), Some(0));
+            assert_eq!(chunks.last().map(|c| c.end), Some(input.len()));
+            for pair in chunks.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+                assert_eq!(input[pair[0].end - 1], b'\n', "chunk size {chunk_size}");
+            }
+        }
+    }
+
+    #[test]
+    fn every_chunk_is_handed_out_once() {
+        let input = b"a;1\n".repeat(100);
+        let queue = ChunkQueue::new(&input, 3, Scheduler::Stealing, 16);
+        let mut seen: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..3)
+                .map(|worker| scope.spawn({
+                    let queue = &queue;
+                    move || queue.worker(worker).map(|(index, _)| index).collect::<Vec<_>>()
+                }))
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+        seen.sort();
+        assert_eq!(seen, (0..queue.len()).collect::<Vec<_>>());
+
+        let queue = ChunkQueue::new(&input, 3, Scheduler::Static, 16);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.worker(1).map(|(index, _)| index).collect::<Vec<_>>(), [1]);
+    }
+}
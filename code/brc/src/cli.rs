@@ -0,0 +1,615 @@
+//! Command-line options shared by the variants. Each binary flattens the groups it supports
+//! into its own `Args`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use clap::Args;
+use crate::ascii::Scale;
+use crate::blocks::{Backend, BlockOptions};
+use crate::chunks::{ChunkQueue, Scheduler};
+use crate::error::ErrorPolicy;
+use crate::export::{write_csv, Sparklines};
+use crate::histogram::{Histogram, Percentile};
+use crate::input::{expand, is_stream, reader, Input, STDIN};
+use crate::mapping::{advise, Advice};
+use crate::ranking::{rank, RankBy};
+use crate::rollup::Regions;
+use crate::scan::clean_line;
+use crate::schema::{parse_delimiter, Column, Schema};
+use crate::sketch::Sketch;
+use crate::spill::Spill;
+use crate::stats::{Accumulator, Distribution};
+use crate::threads::{available_cpus, pin_to_core, CoreList};
+use crate::timeseries::{Span, TimeWindows};
+use crate::validate::{InvalidValueAction, ValueFilter};
+
+#[derive(Args, Debug, Clone)]
+pub struct ErrorArgs {
+    /// What to do with lines that fail to parse: fail, skip, collect (the first 10) or collect:N
+    #[arg(long, default_value = "fail")]
+    pub on_error: ErrorPolicy,
+
+    /// Treat CRLF line endings, a byte order mark and blank lines as bad lines instead of
+    /// quietly tolerating them
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ScaleArgs {
+    /// Digits after the decimal point in the input; values with more are rejected
+    #[arg(long, default_value_t)]
+    pub scale: Scale,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ValueArgs {
+    /// Smallest temperature to accept
+    #[arg(long, allow_negative_numbers = true)]
+    pub min_value: Option<f32>,
+
+    /// Largest temperature to accept
+    #[arg(long, allow_negative_numbers = true)]
+    pub max_value: Option<f32>,
+
+    /// What to do with NaN, infinite or out-of-range temperatures
+    #[arg(long, value_enum, default_value_t)]
+    pub invalid_values: InvalidValueAction,
+}
+
+impl ValueArgs {
+    pub fn filter(&self) -> ValueFilter {
+        ValueFilter {
+            min: self.min_value,
+            max: self.max_value,
+            action: self.invalid_values,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct StatsArgs {
+    /// Also print each station's variance and standard deviation
+    #[arg(long)]
+    pub stddev: bool,
+
+    /// Also print each station's median
+    #[arg(long)]
+    pub median: bool,
+
+    /// Also print these percentiles of each station's readings, after any median; on its own,
+    /// the 1st, 5th, 95th and 99th
+    #[arg(long, value_delimiter = ',', num_args = 0.., default_missing_values = ["1", "5", "95", "99"])]
+    pub percentiles: Vec<Percentile>,
+
+    /// Estimate percentiles with a sketch, accurate to within this fraction of each value (0.01
+    /// on its own), rather than counting every reading; for values too finely grained to count
+    #[arg(long, num_args = 0..=1, default_missing_value = "0.01", value_parser = parse_accuracy)]
+    pub sketch: Option<f64>,
+}
+
+impl StatsArgs {
+    /// The percentiles to print, starting with the median if it was asked for.
+    pub fn percentiles(&self) -> Vec<Percentile> {
+        self.median.then_some(Percentile::MEDIAN).into_iter().chain(self.percentiles.iter().copied()).collect()
+    }
+
+    /// An empty accumulator, which keeps track of the distribution if there are percentiles to
+    /// work out.
+    pub fn accumulator(&self) -> Accumulator {
+        if !self.median && self.percentiles.is_empty() {
+            return Accumulator::default();
+        }
+        Accumulator::with_distribution(match self.sketch {
+            Some(accuracy) => Distribution::Sketch(Sketch::new(accuracy)),
+            None => Distribution::Exact(Histogram::default()),
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RankArgs {
+    /// Put the stations in this order instead of by name, and end each line with the value
+    /// they were ranked by
+    #[arg(long, value_enum)]
+    pub rank_by: Option<RankBy>,
+
+    /// Only print the first this many stations. Regions and countries are ranked too, but
+    /// all of them are printed
+    #[arg(long, requires = "rank_by")]
+    pub top: Option<usize>,
+}
+
+impl RankArgs {
+    /// Sorts `stations` and keeps the ones that make the --top. `station` picks out each one's
+    /// name and readings.
+    pub fn rank<T>(&self, stations: &mut Vec<T>, station: impl Fn(&T) -> (&str, &Accumulator)) {
+        rank(stations, self.rank_by, self.top, station)
+    }
+
+    /// What `readings` were ranked by, if they were.
+    pub fn measure(&self, readings: &Accumulator, scale: Scale) -> Option<String> {
+        self.rank_by.map(|by| by.measure(readings, scale))
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Write each station's distribution to this CSV file, one row per bin
+    #[arg(long, conflicts_with = "sketch")]
+    pub histogram_csv: Option<PathBuf>,
+
+    /// Print a sparkline of each station's distribution after the results
+    #[arg(long, conflicts_with = "sketch")]
+    pub sparklines: bool,
+
+    /// How wide the bins of an exported distribution are
+    #[arg(long, default_value_t = 5.0, value_parser = parse_bin_width)]
+    pub bin_width: f64,
+}
+
+impl ExportArgs {
+    /// An empty accumulator for `stats`, which keeps an exact histogram if there are
+    /// distributions to export.
+    pub fn accumulator(&self, stats: &StatsArgs) -> Accumulator {
+        if self.histogram_csv.is_none() && !self.sparklines {
+            return stats.accumulator();
+        }
+        Accumulator::with_distribution(Distribution::Exact(Histogram::default()))
+    }
+
+    /// Writes out the distributions of `stations`, and returns their sparklines if they're to
+    /// be printed.
+    pub fn export(&self, stations: &[(&str, &Accumulator)], scale: Scale) -> io::Result<Option<Sparklines>> {
+        let width = ((self.bin_width * scale.factor() as f64).round() as i64).max(1);
+        if let Some(path) = &self.histogram_csv {
+            write_csv(BufWriter::new(File::create(path)?), stations, width, scale)?;
+        }
+        Ok(self.sparklines.then(|| Sparklines::new(stations, width, scale)))
+    }
+}
+
+fn parse_bin_width(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|width| width.is_finite() && *width > 0.0)
+        .ok_or_else(|| format!("invalid bin width {s:?} (expected a number more than zero)"))
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RegionArgs {
+    /// Also add the stations up by region and by country, from a file of
+    /// `station;region;country` lines. Stations that aren't in it are added up as "unmapped"
+    #[arg(long)]
+    pub regions: Option<PathBuf>,
+}
+
+impl RegionArgs {
+    /// The table of regions, if there is one.
+    pub fn regions(&self) -> io::Result<Option<Regions>> {
+        let Some(path) = &self.regions else {
+            return Ok(None);
+        };
+        Regions::open(path)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SpillArgs {
+    /// Keep at most this much of the readings in memory (e.g. 256M), sorting the rest out to
+    /// disk and merging it back for exact percentiles
+    #[arg(long, value_parser = parse_size)]
+    pub spill_memory: Option<usize>,
+
+    /// Where to write the sorted readings; the system's temporary directory by default
+    #[arg(long, requires = "spill_memory")]
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl SpillArgs {
+    /// Somewhere to put the readings, if memory is limited.
+    pub fn spill(&self) -> io::Result<Option<Spill>> {
+        let Some(memory) = self.spill_memory else {
+            return Ok(None);
+        };
+        let parent = self.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        Spill::new(&parent, memory).map(Some)
+    }
+}
+
+/// Parses a relative accuracy such as `0.01`, which must be between 0 and 1.
+fn parse_accuracy(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|accuracy| *accuracy > 0.0 && *accuracy < 1.0)
+        .ok_or_else(|| format!("invalid accuracy {s:?} (expected a fraction between 0 and 1, such as 0.01)"))
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SchemaArgs {
+    /// Field delimiter: a single character, or `tab`
+    #[arg(long, default_value = ";", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+
+    /// Column holding the station name: a position counting from 1, or a name from the header
+    #[arg(long, default_value = "1")]
+    pub station_column: Column,
+
+    /// Column holding the temperature: a position counting from 1, or a name from the header
+    #[arg(long, default_value = "2")]
+    pub value_column: Column,
+
+    /// Column holding when each reading was taken, for --time-window: a position counting from
+    /// 1, or a name from the header
+    #[arg(long)]
+    pub time_column: Option<Column>,
+
+    /// The first line is a header row
+    #[arg(long)]
+    pub header: bool,
+
+    /// Quote character for fields containing the delimiter, such as `"`
+    #[arg(long, value_parser = parse_delimiter)]
+    pub quote: Option<u8>,
+}
+
+impl SchemaArgs {
+    /// Works out the schema. `start` is the beginning of the input; its first line is only
+    /// looked at if a column is picked by name.
+    pub fn schema(&self, start: &[u8]) -> Result<Schema, String> {
+        if self.quote == Some(self.delimiter) {
+            return Err("the quote and delimiter can't be the same character".to_string());
+        }
+        let header = if self.header {
+            let line = start.split(|&b| b == b'\n').next().unwrap_or_default();
+            let line = clean_line(line, true, false).ok().flatten().map(|range| &line[range]);
+            line.and_then(|line| std::str::from_utf8(line).ok())
+        } else {
+            None
+        };
+        let schema = Schema {
+            delimiter: self.delimiter,
+            station_column: self.station_column.resolve(header, self.delimiter, self.quote)?,
+            value_column: self.value_column.resolve(header, self.delimiter, self.quote)?,
+            time_column: self.time_column.as_ref().map(|column| column.resolve(header, self.delimiter, self.quote)).transpose()?,
+            header: self.header,
+            quote: self.quote,
+        };
+        if schema.station_column == schema.value_column {
+            return Err("the station and temperature can't be in the same column".to_string());
+        }
+        if schema.time_column.is_some_and(|column| column == schema.station_column || column == schema.value_column) {
+            return Err("the timestamp needs a column of its own".to_string());
+        }
+        Ok(schema)
+    }
+
+    /// Works out the schema for input read a line at a time. The header, if there is one, has
+    /// to be read to do that, so it's put back in front of the rest: the reader handed back
+    /// still starts with it.
+    pub fn schema_of_reader<'a>(&self, mut reader: Box<dyn BufRead + 'a>) -> io::Result<(Schema, Box<dyn BufRead + 'a>)> {
+        // Peeking at the buffer isn't enough: a decompressor or a pipe may not have the whole
+        // of the first line ready yet
+        let mut start = Vec::new();
+        if self.header {
+            reader.read_until(b'\n', &mut start)?;
+        }
+        let schema = self.schema(&start).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok((schema, Box::new(io::Cursor::new(start).chain(reader))))
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TimeArgs {
+    /// Also aggregate each station over back-to-back windows of this long, such as hour, day,
+    /// month or 15m, using the --time-column. Windows are in UTC, and days start at midnight
+    #[arg(long, requires = "time_column")]
+    pub time_window: Option<Span>,
+
+    /// Start a window this often instead, so that they overlap, such as 1h for windows of a
+    /// day
+    #[arg(long, requires = "time_window")]
+    pub slide: Option<Span>,
+}
+
+impl TimeArgs {
+    /// The windows asked for, if any.
+    pub fn windows(&self) -> Result<Option<TimeWindows>, String> {
+        match (self.time_window, self.slide) {
+            (None, _) => Ok(None),
+            (Some(size), None) => Ok(Some(TimeWindows::tumbling(size))),
+            (Some(size), Some(step)) => TimeWindows::sliding(size, step).map(Some),
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ChunkArgs {
+    /// How to share the input between workers: stealing (many small chunks from a shared
+    /// queue) or static (one chunk per worker)
+    #[arg(long, value_enum, default_value_t)]
+    pub scheduler: Scheduler,
+
+    /// Target size of each chunk for the stealing scheduler, such as 512K or 4M
+    #[arg(long, default_value = "1M", value_parser = parse_size)]
+    pub chunk_size: usize,
+}
+
+impl ChunkArgs {
+    /// Splits `buffer` up for `workers` workers.
+    pub fn queue(&self, buffer: &[u8], workers: usize) -> ChunkQueue {
+        ChunkQueue::new(buffer, workers, self.scheduler, self.chunk_size)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SourceArgs {
+    /// Read this file or FIFO instead of the usual measurements file, or - for standard input.
+    /// Only the one: parallel and memory_map, among others, can add up several files,
+    /// directories or globs
+    #[arg(long, short, value_parser = parse_single_input)]
+    pub input: Option<PathBuf>,
+}
+
+impl SourceArgs {
+    /// The path to read: `--input`, or else `default`.
+    pub fn path<'a>(&'a self, default: &'a str) -> &'a Path {
+        self.input.as_deref().unwrap_or(Path::new(default))
+    }
+
+    /// Opens the input for reading a line at a time, decompressing it if need be.
+    pub fn reader(&self, default: &str) -> io::Result<Box<dyn BufRead>> {
+        reader(self.path(default))
+    }
+}
+
+/// An input for the variants that read just the one. A directory or a glob pattern would stand
+/// for several, so they're turned away rather than read as a file.
+fn parse_single_input(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    if path.is_dir() {
+        return Err(format!("{s} is a directory, and only one input can be read"));
+    }
+    if s != STDIN && !path.exists() && s.contains(['*', '?', '[']) {
+        return Err(format!("{s} looks like a glob pattern, and only one input can be read"));
+    }
+    Ok(path)
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SourcesArgs {
+    /// Read these files, directories, glob patterns or FIFOs instead of the usual measurements
+    /// file, or - for standard input. The results are added up across all of them
+    #[arg(long = "input", short, num_args = 1..)]
+    pub inputs: Vec<String>,
+
+    /// Also print the results for each input on its own
+    #[arg(long)]
+    pub per_file: bool,
+}
+
+impl SourcesArgs {
+    /// The files to read: those named by `--input`, or else `default`.
+    pub fn paths(&self, default: &str) -> io::Result<Vec<PathBuf>> {
+        if self.inputs.is_empty() {
+            return Ok(vec![PathBuf::from(default)]);
+        }
+        expand(&self.inputs)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct InputArgs {
+    /// Read the input into memory instead of memory mapping it
+    #[arg(long)]
+    pub no_mmap: bool,
+}
+
+impl InputArgs {
+    /// Opens `path`, decompressing it with up to `threads` threads if it's compressed.
+    pub fn open(&self, path: impl AsRef<Path>, threads: usize) -> io::Result<Input> {
+        Input::open_with(path, !self.no_mmap, threads)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct WindowArgs {
+    /// Read the input a record-aligned window of this size at a time, such as 256M, instead
+    /// of all at once. Caps how much of it is resident, for inputs bigger than memory
+    #[arg(long, value_parser = parse_size)]
+    pub memory_budget: Option<usize>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BackendArgs {
+    /// How to read the input: mmap, or explicit reads into reusable buffers with pread or
+    /// io_uring
+    #[arg(long, value_enum, default_value_t)]
+    pub backend: Backend,
+
+    /// How much the pread and io_uring backends read at a time, such as 16M
+    #[arg(long, default_value = "16M", value_parser = parse_size)]
+    pub block_size: usize,
+
+    /// How many reads the io_uring backend keeps in flight
+    #[arg(long, default_value_t = 4)]
+    pub queue_depth: usize,
+
+    /// Bypass the page cache with O_DIRECT (pread and io_uring only, where supported)
+    #[arg(long)]
+    pub direct: bool,
+}
+
+impl BackendArgs {
+    /// The block reader options for `path`, with `threads` threads to decompress it, or `None`
+    /// to map it. A stream or a compressed file can't be mapped, so it's always read in blocks.
+    pub fn blocks(&self, path: &Path, threads: usize) -> Option<BlockOptions> {
+        let stream = is_stream(path);
+        (self.backend != Backend::Mmap || stream).then_some(BlockOptions {
+            backend: if stream { Backend::Pread } else { self.backend },
+            block_size: self.block_size,
+            queue_depth: self.queue_depth,
+            direct: self.direct,
+            threads,
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AdviceArgs {
+    /// Hints for how the memory map will be read: sequential, willneed and/or hugepage,
+    /// separated by commas
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub advise: Vec<Advice>,
+}
+
+impl AdviceArgs {
+    /// Passes the hints on to the kernel, warning about any it turns down.
+    pub fn apply(&self, buffer: &[u8]) {
+        for &advice in &self.advise {
+            if let Err(e) = advise(buffer, advice) {
+                eprintln!("Couldn't apply {advice:?} hint: {e}");
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct PrefetchArgs {
+    /// Fault pages in on a background thread, ahead of the workers
+    #[arg(long)]
+    pub prefetch: bool,
+
+    /// How many chunks the prefetch thread may run ahead of the workers
+    #[arg(long, default_value_t = 4)]
+    pub prefetch_ahead: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ThreadArgs {
+    /// Number of worker threads. Defaults to the CPUs this process may use, allowing for cgroup
+    /// CPU quotas
+    #[arg(long)]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Pin worker threads to these cores in turn, such as 0,2,4 or 0-3
+    #[arg(long)]
+    pub pin_cores: Option<CoreList>,
+}
+
+impl ThreadArgs {
+    /// How many workers to start. Unless `--threads` says otherwise, `reserved` CPUs are left
+    /// for other threads, such as a receiver. Never less than one.
+    pub fn workers(&self, reserved: usize) -> usize {
+        match self.threads {
+            Some(threads) => threads.get(),
+            None => available_cpus().saturating_sub(reserved).max(1),
+        }
+    }
+
+    /// Pins the calling thread, worker number `worker`, to its core if `--pin-cores` was given.
+    pub fn pin(&self, worker: usize) {
+        if let Some(CoreList(cores)) = &self.pin_cores {
+            let core = cores[worker % cores.len()];
+            if !pin_to_core(core) {
+                eprintln!("Couldn't pin worker {worker} to core {core}");
+            }
+        }
+    }
+}
+
+/// Parses a size in bytes, optionally followed by `K`, `M` or `G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    match digits.parse::<usize>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(0) => Err("size must be more than zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err(format!("invalid size {s:?} (expected bytes, or a number followed by K, M or G)")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use clap::Parser;
+    use flate2::write::GzEncoder;
+    use crate::lines::Lines;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        source: SourceArgs,
+
+        #[command(flatten)]
+        schema: SchemaArgs,
+
+        #[command(flatten)]
+        stats: StatsArgs,
+
+        #[command(flatten)]
+        export: ExportArgs,
+    }
+
+    #[test]
+    fn named_columns_resolve_through_the_reader() {
+        let csv = b"temp,id,\"station name\"\n1.5,x,Oslo\n-2.0,y,\"Lima, Peru\"\n";
+        let mut compressed = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        compressed.write_all(csv).unwrap();
+        for (name, contents) in [("csv", csv.to_vec()), ("csv.gz", compressed.finish().unwrap())] {
+            let path = std::env::temp_dir().join(format!("brc-named-columns-{}.{name}", std::process::id()));
+            File::create(&path).unwrap().write_all(&contents).unwrap();
+            let args = TestArgs::parse_from([
+                "test", "-i", path.to_str().unwrap(), "--delimiter", ",", "--header", "--quote", "\"",
+                "--station-column", "station name", "--value-column", "temp",
+            ]);
+            let (schema, reader) = args.schema.schema_of_reader(args.source.reader("unused").unwrap()).unwrap();
+            let readings: Vec<_> = Lines::new(reader)
+                .schema(schema)
+                .map(|line| {
+                    let line = line.unwrap();
+                    let record = line.record().unwrap();
+                    (record.station.to_string(), record.value.to_string())
+                })
+                .collect();
+            assert_eq!(readings, [("Oslo".to_string(), "1.5".to_string()), ("Lima, Peru".to_string(), "-2.0".to_string())], "{name}");
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn exporting_doesnt_add_percentiles() {
+        for (flags, expected) in [(&["--sparklines"][..], None), (&["--sparklines", "--median"], Some("2.0"))] {
+            let args = TestArgs::parse_from(["test"].iter().chain(flags));
+            let mut readings = args.export.accumulator(&args.stats);
+            [10, 20, 30].into_iter().for_each(|value| readings.add(value));
+            assert!(readings.distribution.is_some());
+            let percentiles = readings.percentiles(&args.stats.percentiles(), Scale::TENTHS);
+            assert_eq!(percentiles.map(|percentiles| percentiles.to_string()).as_deref(), expected, "{flags:?}");
+        }
+    }
+
+    #[test]
+    fn single_inputs_turn_away_several() {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        let error = TestArgs::try_parse_from(["test", "-i", dir]).err().unwrap();
+        assert!(error.to_string().contains("is a directory"), "{error}");
+        let error = TestArgs::try_parse_from(["test", "-i", "no_such_dir/*.txt"]).err().unwrap();
+        assert!(error.to_string().contains("looks like a glob pattern"), "{error}");
+        assert!(TestArgs::try_parse_from(["test", "-i", "a.txt", "b.txt"]).is_err());
+        for input in ["a.txt", "-"] {
+            let args = TestArgs::try_parse_from(["test", "-i", input]).unwrap();
+            assert_eq!(args.source.path("default"), Path::new(input));
+        }
+    }
+}
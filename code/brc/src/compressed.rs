@@ -0,0 +1,280 @@
+//! Reading gzip and zstd compressed input, recognised by its first few bytes.
+//!
+//! A compressed file is one long stream as far as the readers are concerned. Where it's made
+//! of frames (zstd) or members (gzip) that can be found without decompressing them, such as
+//! the output of `pzstd` or `bgzip`, a [`FrameDecoder`] decompresses several at once;
+//! otherwise a single thread decompresses the whole thing in order.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognises a compressed input from its first few bytes.
+    pub fn detect(start: &[u8]) -> Option<Self> {
+        if start.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if start.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Looks at the start of the file at `path`.
+    pub fn of_file(path: &Path) -> io::Result<Option<Self>> {
+        let mut start = Vec::with_capacity(ZSTD_MAGIC.len());
+        File::open(path)?.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut start)?;
+        Ok(Self::detect(&start))
+    }
+
+    /// Decompresses `reader` front to back, every frame or member in turn.
+    pub fn decoder<'a>(self, reader: impl BufRead + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    /// Where each frame of `data` starts and ends, if that can be worked out without
+    /// decompressing them. A gzip member only says how long it is if it was written by BGZF
+    /// tools such as `bgzip`.
+    pub fn frames(self, data: &[u8]) -> Option<Vec<Range<usize>>> {
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            let len = match self {
+                Compression::Gzip => bgzf_member_len(&data[start..])?,
+                Compression::Zstd => zstd::zstd_safe::find_frame_compressed_size(&data[start..]).ok()?,
+            };
+            if len == 0 || start + len > data.len() {
+                return None;
+            }
+            frames.push(start..start + len);
+            start += len;
+        }
+        Some(frames)
+    }
+
+    fn decode_frame(self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        self.decoder(frame)?.read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+}
+
+/// The length of the BGZF member at the start of `data`, from its `BC` extra field.
+fn bgzf_member_len(data: &[u8]) -> Option<usize> {
+    const FEXTRA: u8 = 4;
+    // ID1 ID2 CM FLG MTIME(4) XFL OS XLEN(2), then the extra subfields.
+    if data.len() < 12 || data[..2] != GZIP_MAGIC || data[3] & FEXTRA == 0 {
+        return None;
+    }
+    let xlen = u16::from_le_bytes([data[10], data[11]]) as usize;
+    let mut extra = data.get(12..12 + xlen)?;
+    while extra.len() >= 4 {
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        if &extra[..2] == b"BC" && len == 2 {
+            let size = extra.get(4..6)?;
+            return Some(u16::from_le_bytes([size[0], size[1]]) as usize + 1);
+        }
+        extra = extra.get(4 + len..)?;
+    }
+    None
+}
+
+/// `reader`, decompressed if it starts like a compressed stream.
+pub fn decompressed(mut reader: impl Read + Send + 'static) -> io::Result<Box<dyn BufRead + Send>> {
+    // A pipe may hand over fewer bytes than we asked for, so keep going until there are enough
+    // to tell.
+    let mut start = vec![0; ZSTD_MAGIC.len()];
+    let mut filled = 0;
+    while filled < start.len() {
+        match reader.read(&mut start[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    start.truncate(filled);
+    let compression = Compression::detect(&start);
+    let reader = BufReader::new(io::Cursor::new(start).chain(reader));
+    Ok(match compression {
+        Some(compression) => Box::new(BufReader::new(compression.decoder(reader)?)),
+        None => Box::new(reader),
+    })
+}
+
+/// Decompresses the frames of a compressed input on `workers` threads at once, and reads them
+/// back in order.
+///
+/// The workers only run a few frames ahead of the reader, so only those are held in memory.
+pub struct FrameDecoder {
+    decoded: Receiver<(usize, io::Result<Vec<u8>>)>,
+    /// Frames that were finished before the ones in front of them.
+    waiting: BTreeMap<usize, io::Result<Vec<u8>>>,
+    current: io::Cursor<Vec<u8>>,
+    next: usize,
+    frames: usize,
+    /// How many frames have been read; the workers stay close behind this.
+    read: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FrameDecoder {
+    pub fn new<D>(data: Arc<D>, compression: Compression, frames: Vec<Range<usize>>, workers: usize) -> Self
+    where
+        D: Deref<Target = [u8]> + Send + Sync + 'static,
+    {
+        let workers = workers.max(1);
+        let ahead = workers * 2;
+        let frame_count = frames.len();
+        let frames = Arc::new(frames);
+        let claimed = Arc::new(AtomicUsize::new(0));
+        let read = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, decoded) = mpsc::channel();
+        for _ in 0..workers {
+            let (data, frames, claimed, read, stop, sender) =
+                (data.clone(), frames.clone(), claimed.clone(), read.clone(), stop.clone(), sender.clone());
+            thread::spawn(move || loop {
+                let index = claimed.fetch_add(1, Ordering::Relaxed);
+                if index >= frames.len() {
+                    return;
+                }
+                while index >= read.load(Ordering::Relaxed) + ahead {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    thread::sleep(Duration::from_micros(100));
+                }
+                let result = compression.decode_frame(&data[frames[index].clone()]);
+                if stop.load(Ordering::Relaxed) || sender.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        FrameDecoder {
+            decoded,
+            waiting: BTreeMap::new(),
+            current: io::Cursor::new(Vec::new()),
+            next: 0,
+            frames: frame_count,
+            read,
+            stop,
+        }
+    }
+}
+
+impl Read for FrameDecoder {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buffer)?;
+            if n > 0 || buffer.is_empty() || self.next == self.frames {
+                return Ok(n);
+            }
+            let frame = loop {
+                if let Some(frame) = self.waiting.remove(&self.next) {
+                    break frame;
+                }
+                let (index, frame) = self
+                    .decoded
+                    .recv()
+                    .map_err(|_| io::Error::other("a decompression thread stopped unexpectedly"))?;
+                self.waiting.insert(index, frame);
+            };
+            self.next += 1;
+            self.read.store(self.next, Ordering::Relaxed);
+            self.current = io::Cursor::new(frame?);
+        }
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Decompresses all of `data`, several frames at a time where it can.
+pub fn decompress<D>(data: D, compression: Compression, workers: usize) -> io::Result<Vec<u8>>
+where
+    D: Deref<Target = [u8]> + Send + Sync + 'static,
+{
+    let mut decoded = Vec::new();
+    match compression.frames(&data).filter(|frames| frames.len() > 1 && workers > 1) {
+        Some(frames) => FrameDecoder::new(Arc::new(data), compression, frames, workers).read_to_end(&mut decoded)?,
+        None => compression.decoder(&data[..])?.read_to_end(&mut decoded)?,
+    };
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn contents() -> Vec<u8> {
+        (0..20_000).flat_map(|i| format!("Station {};{}.{}\n", i % 300, i % 50, i % 10).into_bytes()).collect()
+    }
+
+    fn bgzf_member(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .extra(vec![b'B', b'C', 2, 0, 0, 0])
+            .write(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        let mut member = encoder.finish().unwrap();
+        let size = (member.len() - 1) as u16;
+        member[16..18].copy_from_slice(&size.to_le_bytes());
+        member
+    }
+
+    #[test]
+    fn frames_are_found_and_decoded_in_order() {
+        let contents = contents();
+        let pieces: Vec<&[u8]> = contents.chunks(10_000).collect();
+        let zstd: Vec<u8> = pieces.iter().flat_map(|piece| zstd::encode_all(*piece, 1).unwrap()).collect();
+        let gzip: Vec<u8> = pieces.iter().flat_map(|piece| bgzf_member(piece)).collect();
+        for (compressed, compression) in [(zstd, Compression::Zstd), (gzip, Compression::Gzip)] {
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+            assert_eq!(compression.frames(&compressed).unwrap().len(), pieces.len());
+            for workers in [1, 3] {
+                assert_eq!(decompress(compressed.clone(), compression, workers).unwrap(), contents);
+            }
+            let mut streamed = Vec::new();
+            decompressed(io::Cursor::new(compressed)).unwrap().read_to_end(&mut streamed).unwrap();
+            assert_eq!(streamed, contents);
+        }
+    }
+
+    #[test]
+    fn plain_gzip_members_are_read_one_after_another() {
+        let contents = contents();
+        let mut gzip = Vec::new();
+        for piece in contents.chunks(30_000) {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(piece).unwrap();
+            gzip.extend(encoder.finish().unwrap());
+        }
+        assert_eq!(Compression::Gzip.frames(&gzip), None);
+        assert_eq!(decompress(gzip, Compression::Gzip, 4).unwrap(), contents);
+        assert!(Compression::detect(&contents).is_none());
+    }
+}
@@ -0,0 +1,195 @@
+//! Bad input lines: where they are, what was wrong with them, and what to do about it.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// What was wrong with a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line has no delimiter (the one given) at all, so there's nothing to split.
+    MissingDelimiter(u8),
+    /// The line has fewer columns than the schema needs (1-based column number).
+    MissingColumn(usize),
+    /// A quoted field is missing its closing quote, or has text after it.
+    UnterminatedQuote,
+    /// A byte that can't appear in a number.
+    InvalidByte(u8),
+    /// The temperature is empty, or is just a sign and/or decimal point.
+    NoDigits,
+    /// More digits after the decimal point than we can represent.
+    TooManyFractionDigits,
+    /// The temperature doesn't fit in our fixed-point representation, or is outside the range
+    /// we were asked to accept.
+    OutOfRange,
+    /// The temperature is NaN or infinite.
+    NonFinite,
+    /// `str::parse` didn't like the temperature.
+    InvalidNumber,
+    /// The timestamp isn't one we understand, or isn't a real date and time.
+    InvalidTimestamp,
+    /// The line isn't valid UTF-8.
+    InvalidUtf8,
+    /// Strict mode: the line ends in CRLF.
+    CarriageReturn,
+    /// Strict mode: the input starts with a UTF-8 byte order mark.
+    ByteOrderMark,
+    /// Strict mode: the line is empty.
+    BlankLine,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingDelimiter(delimiter) => {
+                write!(f, "missing '{}' delimiter", (*delimiter as char).escape_default())
+            }
+            ParseErrorKind::MissingColumn(column) => write!(f, "missing column {column}"),
+            ParseErrorKind::UnterminatedQuote => write!(f, "badly quoted field"),
+            ParseErrorKind::InvalidByte(byte) => write!(f, "unexpected byte 0x{byte:02x} in temperature"),
+            ParseErrorKind::NoDigits => write!(f, "temperature has no digits"),
+            ParseErrorKind::TooManyFractionDigits => write!(f, "too many fractional digits"),
+            ParseErrorKind::OutOfRange => write!(f, "temperature out of range"),
+            ParseErrorKind::NonFinite => write!(f, "temperature is not finite"),
+            ParseErrorKind::InvalidNumber => write!(f, "temperature is not a number"),
+            ParseErrorKind::InvalidTimestamp => write!(f, "invalid timestamp"),
+            ParseErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            ParseErrorKind::CarriageReturn => write!(f, "CRLF line ending"),
+            ParseErrorKind::ByteOrderMark => write!(f, "byte order mark"),
+            ParseErrorKind::BlankLine => write!(f, "blank line"),
+        }
+    }
+}
+
+/// A line we couldn't parse, and exactly where it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the start of the line, from the start of the input.
+    pub offset: u64,
+    /// 1-based line number.
+    pub line: u64,
+    /// The offending line (lossily converted if it isn't UTF-8).
+    pub text: String,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, offset: u64, line: u64, text: &[u8]) -> Self {
+        Self {
+            offset,
+            line,
+            text: String::from_utf8_lossy(text).to_string(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} (byte {}): {}: {:?}", self.line, self.offset, self.kind, self.text)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// What to do when a line fails to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stop at the first bad line.
+    #[default]
+    FailFast,
+    /// Skip bad lines, only counting them.
+    Skip,
+    /// Skip bad lines, keeping the first N for the report.
+    Collect(usize),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    /// Accepts `fail`, `skip`, `collect` (the first 10 lines) or `collect:N`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fail" => Ok(ErrorPolicy::FailFast),
+            None if s == "skip" => Ok(ErrorPolicy::Skip),
+            None if s == "collect" => Ok(ErrorPolicy::Collect(10)),
+            Some(("collect", n)) => n
+                .parse()
+                .map(ErrorPolicy::Collect)
+                .map_err(|_| format!("invalid line count {n:?}")),
+            _ => Err(format!("unknown error policy {s:?} (expected fail, skip, collect or collect:N)")),
+        }
+    }
+}
+
+/// Tallies bad lines according to an [`ErrorPolicy`].
+///
+/// Each worker keeps its own report; they're merged afterwards in input order, so "the first N"
+/// means the first N in the file no matter how many threads found them.
+#[derive(Debug, Default)]
+pub struct ErrorReport {
+    policy: ErrorPolicy,
+    skipped: u64,
+    errors: Vec<ParseError>,
+}
+
+impl ErrorReport {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            skipped: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Applies the policy to a bad line. Only [`ErrorPolicy::FailFast`] hands the error back.
+    pub fn record(&mut self, error: ParseError) -> Result<(), ParseError> {
+        match self.policy {
+            ErrorPolicy::FailFast => return Err(error),
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Collect(limit) => {
+                if self.errors.len() < limit {
+                    self.errors.push(error);
+                }
+            }
+        }
+        self.skipped += 1;
+        Ok(())
+    }
+
+    /// Folds in the report for a later part of the input, which started `lines_before` lines in.
+    pub fn merge(&mut self, other: ErrorReport, lines_before: u64) {
+        self.skipped += other.skipped;
+        if let ErrorPolicy::Collect(limit) = self.policy {
+            for mut error in other.errors {
+                if self.errors.len() == limit {
+                    break;
+                }
+                error.line += lines_before;
+                self.errors.push(error);
+            }
+        }
+    }
+
+    /// How many lines were skipped.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The bad lines kept for the report.
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Skipped {} bad line(s)", self.skipped)?;
+        for error in &self.errors {
+            write!(f, "\n  {error}")?;
+        }
+        if (self.errors.len() as u64) < self.skipped && !self.errors.is_empty() {
+            write!(f, "\n  ...")?;
+        }
+        Ok(())
+    }
+}
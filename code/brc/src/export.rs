@@ -0,0 +1,161 @@
+//! Each station's distribution, counted in bins, as CSV or as a sparkline per station.
+
+use std::fmt;
+use std::io::{self, Write};
+use crate::ascii::Scale;
+use crate::histogram::Histogram;
+use crate::stats::{Accumulator, Distribution};
+
+/// Counts of readings in bins of the same width, each starting on a multiple of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bins {
+    /// The number of the first bin: it starts at `first * width`.
+    first: i64,
+    width: i64,
+    counts: Vec<u64>,
+}
+
+impl Bins {
+    /// `histogram`'s readings, counted in bins `width` wide, covering every bin from the one
+    /// that holds `low` to the one that holds `high`. Readings outside those bins are left out.
+    pub fn new(histogram: &Histogram, width: i64, low: i64, high: i64) -> Self {
+        let first = low.div_euclid(width);
+        let last = high.div_euclid(width).max(first);
+        let mut counts = vec![0; (last - first + 1) as usize];
+        for (value, count) in histogram.values() {
+            let bin = (value as i64).div_euclid(width) - first;
+            if let Some(total) = usize::try_from(bin).ok().and_then(|bin| counts.get_mut(bin)) {
+                *total += count;
+            }
+        }
+        Bins { first, width, counts }
+    }
+
+    /// Where each bin starts and (exclusively) ends, and how many readings it holds.
+    pub fn iter(&self) -> impl Iterator<Item = (i64, i64, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(index, &count)| {
+            let start = (self.first + index as i64) * self.width;
+            (start, start + self.width, count)
+        })
+    }
+
+    /// One character per bin, taller the more readings it holds; empty bins are blank, so
+    /// even a single stray reading shows up.
+    pub fn sparkline(&self) -> String {
+        const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let most = self.counts.iter().copied().max().unwrap_or(0).max(1);
+        self.counts
+            .iter()
+            .map(|&count| match count {
+                0 => ' ',
+                _ => BLOCKS[((count * BLOCKS.len() as u64).div_ceil(most) - 1) as usize],
+            })
+            .collect()
+    }
+}
+
+/// The exact histogram an accumulator kept, if it kept one.
+fn histogram(readings: &Accumulator) -> Option<&Histogram> {
+    match readings.distribution.as_deref() {
+        Some(Distribution::Exact(histogram)) => Some(histogram),
+        _ => None,
+    }
+}
+
+/// Writes each station's distribution as CSV, one row per bin from its lowest reading to its
+/// highest, with the bins `width` wide at `scale`.
+pub fn write_csv(mut writer: impl Write, stations: &[(&str, &Accumulator)], width: i64, scale: Scale) -> io::Result<()> {
+    writeln!(writer, "station,bin_start,bin_end,count")?;
+    for &(station, readings) in stations {
+        let Some(histogram) = histogram(readings) else {
+            continue;
+        };
+        let station = csv_field(station);
+        for (start, end, count) in Bins::new(histogram, width, readings.min as i64, readings.max as i64).iter() {
+            writeln!(writer, "{station},{},{},{count}", scale.format(start), scale.format(end))?;
+        }
+    }
+    writer.flush()
+}
+
+/// Quotes a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A sparkline for each station, all over the same bins so that they line up.
+pub struct Sparklines {
+    low: i64,
+    high: i64,
+    width: i64,
+    scale: Scale,
+    lines: Vec<(String, String)>,
+}
+
+impl Sparklines {
+    pub fn new(stations: &[(&str, &Accumulator)], width: i64, scale: Scale) -> Self {
+        let low = stations.iter().map(|(_, readings)| readings.min as i64).min().unwrap_or(0);
+        let high = stations.iter().map(|(_, readings)| readings.max as i64).max().unwrap_or(0);
+        let lines = stations
+            .iter()
+            .filter_map(|&(station, readings)| {
+                let bins = Bins::new(histogram(readings)?, width, low, high);
+                Some((station.to_string(), bins.sparkline()))
+            })
+            .collect();
+        Sparklines { low, high, width, scale, lines }
+    }
+}
+
+impl fmt::Display for Sparklines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = self.scale;
+        write!(
+            f,
+            "Distributions from {} to {} in bins of {}:",
+            scale.format(self.low),
+            scale.format(self.high),
+            scale.format(self.width),
+        )?;
+        let name_width = self.lines.iter().map(|(station, _)| station.chars().count()).max().unwrap_or(0);
+        for (station, sparkline) in &self.lines {
+            write!(f, "\n{station:<name_width$} │{sparkline}│")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accumulator(readings: &[i32]) -> Accumulator {
+        let mut accumulator = Accumulator::with_distribution(Distribution::Exact(Histogram::default()));
+        readings.iter().for_each(|&value| accumulator.add(value));
+        accumulator
+    }
+
+    #[test]
+    fn readings_land_in_the_right_bins() {
+        let readings = accumulator(&[-15, -10, -1, 0, 9, 10, 10, 10, 49]);
+        let bins = Bins::new(histogram(&readings).unwrap(), 10, -15, 49);
+        let expected = [(-20, -10, 1), (-10, 0, 2), (0, 10, 2), (10, 20, 3), (20, 30, 0), (30, 40, 0), (40, 50, 1)];
+        assert_eq!(bins.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(bins.sparkline(), "▃▆▆█  ▃");
+    }
+
+    #[test]
+    fn csv_has_a_row_per_bin() {
+        let (a, b) = (accumulator(&[12, 15, 31]), accumulator(&[-5]));
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &[("A", &a), ("B, the second", &b)], 10, Scale::TENTHS).unwrap();
+        let expected = "station,bin_start,bin_end,count\n\
+                        A,1.0,2.0,2\nA,2.0,3.0,0\nA,3.0,4.0,1\n\
+                        \"B, the second\",-1.0,0.0,1\n";
+        assert_eq!(String::from_utf8(csv).unwrap(), expected);
+    }
+}
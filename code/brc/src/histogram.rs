@@ -0,0 +1,192 @@
+//! Exact percentiles, from counting how many times each reading turns up.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use crate::ascii::{parse_general, Fixed, Scale};
+
+/// The smallest and largest readings with a bucket of their own: -99.9 and 99.9 at the
+/// challenge's scale of tenths.
+const LOWEST: i32 = -999;
+const HIGHEST: i32 = 999;
+const BUCKETS: usize = (HIGHEST - LOWEST + 1) as usize;
+
+/// How many times each reading has been seen.
+///
+/// Readings are fixed-point, so every challenge temperature is a whole number of tenths from
+/// -999 to 999 and 1,999 counters cover them all however many readings there are. That makes
+/// percentiles exact, and two histograms merge by adding their counters together. Readings
+/// outside that range, which other scales or unfiltered input can produce, are counted
+/// separately. The counters aren't allocated until there's something to count, so stations
+/// that are set up in advance and never seen cost next to nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    outside: BTreeMap<i32, u64>,
+    total: u64,
+}
+
+impl Histogram {
+    #[inline(always)]
+    pub fn add(&mut self, value: i32) {
+        if (LOWEST..=HIGHEST).contains(&value) {
+            if self.counts.is_empty() {
+                self.counts = vec![0; BUCKETS];
+            }
+            self.counts[(value - LOWEST) as usize] += 1;
+        } else {
+            *self.outside.entry(value).or_default() += 1;
+        }
+        self.total += 1;
+    }
+
+    /// Adds in everything `other` has seen.
+    pub fn merge(&mut self, other: &Histogram) {
+        if self.counts.is_empty() {
+            self.counts = other.counts.clone();
+        } else {
+            for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+                *count += other;
+            }
+        }
+        for (&value, &count) in &other.outside {
+            *self.outside.entry(value).or_default() += count;
+        }
+        self.total += other.total;
+    }
+
+    /// How many readings there are.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Each different reading, smallest first, with how many times it was seen.
+    pub fn values(&self) -> impl Iterator<Item = (i32, u64)> + '_ {
+        let below = self.outside.range(..LOWEST);
+        let above = self.outside.range(HIGHEST + 1..);
+        let inside = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(index, &count)| (index as i32 + LOWEST, count));
+        below.map(|(&value, &count)| (value, count)).chain(inside).chain(above.map(|(&value, &count)| (value, count)))
+    }
+
+    /// The smallest reading that at least `percentile` percent of the readings are no bigger
+    /// than (the nearest-rank method), so it's always one of the readings themselves. `None` if
+    /// there aren't any.
+    pub fn percentile(&self, percentile: Percentile) -> Option<i32> {
+        let rank = percentile.rank(self.total)?;
+        let mut seen = 0;
+        self.values().find_map(|(value, count)| {
+            seen += count;
+            (seen >= rank).then_some(value)
+        })
+    }
+
+    /// Each of `percentiles`, ready to print at `scale`.
+    pub fn percentiles(&self, percentiles: &[Percentile], scale: Scale) -> Percentiles {
+        Percentiles(
+            percentiles
+                .iter()
+                .filter_map(|&percentile| self.percentile(percentile))
+                .map(|value| scale.format(value as i64))
+                .collect(),
+        )
+    }
+}
+
+/// A percentile from 0 to 100, such as `99.9`, held exactly in millionths so that working out
+/// its rank involves no rounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentile {
+    millionths: u64,
+}
+
+impl Percentile {
+    const DIGITS: u32 = 6;
+    const HUNDRED: u64 = 100_000_000;
+
+    pub const MEDIAN: Percentile = Percentile { millionths: Self::HUNDRED / 2 };
+
+    /// The position, counting from 1, of this percentile among `count` sorted readings.
+    pub(crate) fn rank(&self, count: u64) -> Option<u64> {
+        let rank = (count as u128 * self.millionths as u128).div_ceil(Self::HUNDRED as u128);
+        (count > 0).then_some((rank as u64).max(1))
+    }
+}
+
+impl FromStr for Percentile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_general(s.as_bytes(), Self::DIGITS)
+            .ok()
+            .and_then(|millionths| u64::try_from(millionths).ok())
+            .filter(|&millionths| millionths <= Self::HUNDRED)
+            .map(|millionths| Percentile { millionths })
+            .ok_or_else(|| format!("a percentile must be a number from 0 to 100 with at most {} decimal places", Self::DIGITS))
+    }
+}
+
+/// Some percentiles of a station's readings, which print separated by semicolons.
+pub struct Percentiles(pub Vec<Fixed>);
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, value) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ";")?;
+            }
+            write!(f, "{value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The nearest-rank percentile, straight from the sorted readings.
+    fn sorted_percentile(sorted: &[i32], percentile: f64) -> i32 {
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil().max(1.0) as usize;
+        sorted[rank - 1]
+    }
+
+    #[test]
+    fn percentiles_match_sorting_the_readings() {
+        let readings: Vec<i32> = (0..10_001).map(|i| (i * 7919 % 2501) - 1250).collect();
+        let mut sorted = readings.clone();
+        sorted.sort();
+        let mut parts: Vec<Histogram> = readings
+            .chunks(1000)
+            .map(|chunk| {
+                let mut part = Histogram::default();
+                chunk.iter().for_each(|&value| part.add(value));
+                part
+            })
+            .collect();
+        let mut histogram = parts.pop().unwrap();
+        parts.iter().for_each(|part| histogram.merge(part));
+        assert_eq!(histogram.count(), readings.len() as u64);
+        for percentile in ["0", "1", "5", "25", "50", "75", "95", "99", "99.9", "100"] {
+            let expected = sorted_percentile(&sorted, percentile.parse().unwrap());
+            assert_eq!(histogram.percentile(percentile.parse().unwrap()), Some(expected), "p{percentile}");
+        }
+        assert_eq!(histogram.percentile(Percentile::MEDIAN), Some(sorted[5000]));
+    }
+
+    #[test]
+    fn ranks_are_exact() {
+        let p7: Percentile = "7".parse().unwrap();
+        // 0.07 * 100 is a shade over 7 in floating point, which would give rank 8
+        assert_eq!(p7.rank(100), Some(7));
+        assert_eq!("99.9".parse::<Percentile>().unwrap().rank(1000), Some(999));
+        assert_eq!(Percentile::MEDIAN.rank(4), Some(2));
+        assert_eq!(Percentile::MEDIAN.rank(0), None);
+        assert!("100.1".parse::<Percentile>().is_err());
+        assert!("-1".parse::<Percentile>().is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//! Opening the input: memory mapped where we can, read into memory where we can't.
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use memmap2::{Mmap, MmapOptions};
+use crate::compressed::{decompress, decompressed, Compression};
+use crate::threads::available_cpus;
+
+pub(crate) enum Data {
+    Mapped(Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Data::Mapped(map) => map,
+            Data::Buffered(buffer) => buffer,
+        }
+    }
+}
+
+/// Stands for standard input wherever an input path is expected.
+pub const STDIN: &str = "-";
+
+/// Does `path` have to be read front to back, like standard input, a pipe, a FIFO or a
+/// compressed file, rather than being a file we can map or read anywhere in?
+pub fn is_stream(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    path == Path::new(STDIN)
+        || std::fs::metadata(path).is_ok_and(|metadata| !metadata.is_file())
+        || Compression::of_file(path).is_ok_and(|compression| compression.is_some())
+}
+
+/// Opens `path`, or standard input given [`STDIN`], for reading a line at a time, decompressing
+/// it if need be.
+pub fn reader(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if path == Path::new(STDIN) {
+        Ok(decompressed(io::stdin())?)
+    } else {
+        Ok(decompressed(File::open(path)?)?)
+    }
+}
+
+/// Turns the inputs named on the command line into the files to read, in order: a directory
+/// stands for the files in it, sorted by name, and a glob pattern for the files it matches.
+/// Anything else, including [`STDIN`], is passed on as it is. A file named twice is only read
+/// once.
+pub fn expand(inputs: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if !hidden && !entry.file_type()?.is_dir() {
+                    files.push(entry.path());
+                }
+            }
+            files.sort();
+            paths.extend(files);
+        } else if input != STDIN && !path.exists() && input.contains(['*', '?', '[']) {
+            let pattern = glob::glob(input).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{input}: {e}")))?;
+            let mut files = Vec::new();
+            for file in pattern {
+                let file = file.map_err(io::Error::from)?;
+                if !file.is_dir() {
+                    files.push(file);
+                }
+            }
+            if files.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("nothing matches {input}")));
+            }
+            paths.extend(files);
+        } else {
+            paths.push(path.to_path_buf());
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    Ok(paths)
+}
+
+/// An open, locked input file, and what it looked like when we opened it.
+pub(crate) struct Source {
+    pub(crate) path: PathBuf,
+    file: File,
+    pub(crate) len: u64,
+    modified: Option<SystemTime>,
+    pub(crate) is_file: bool,
+}
+
+impl Source {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Not every filesystem supports locks; if this one doesn't, the check in `verify`
+        // is all we've got.
+        let _ = file.lock_shared();
+        let metadata = file.metadata()?;
+        Ok(Source {
+            path: path.to_path_buf(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            is_file: metadata.is_file(),
+            file,
+        })
+    }
+
+    /// Maps or reads `len` bytes starting at `offset`.
+    pub(crate) fn read(&self, offset: u64, len: usize, map: bool) -> io::Result<Data> {
+        if map && self.is_file && len > 0 {
+            // SAFETY: the map is only sound as long as nobody truncates the file while it's
+            // mapped. We can't rule that out entirely, but the lock keeps cooperating writers
+            // away and `verify` reports anyone else.
+            if let Ok(map) = unsafe { MmapOptions::new().offset(offset).len(len).map(&self.file) } {
+                return Ok(Data::Mapped(map));
+            }
+        }
+        let mut buffer = vec![0; len];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        Ok(Data::Buffered(buffer))
+    }
+
+    pub(crate) fn verify(&self) -> io::Result<()> {
+        // A pipe's size and times say nothing about what went through it.
+        if !self.is_file {
+            return Ok(());
+        }
+        let metadata = self.file.metadata()?;
+        if metadata.len() != self.len || metadata.modified().ok() != self.modified {
+            return Err(io::Error::other(format!("{} changed while it was being read", self.path.display())));
+        }
+        Ok(())
+    }
+}
+
+/// The whole input as one slice of bytes.
+///
+/// A memory map goes wrong if the file underneath it shrinks: touching a page past the new end
+/// kills the process with `SIGBUS`. So while the input is open we hold a shared advisory lock
+/// on it, which makes a well-behaved writer wait until we're done, and [`Input::verify`]
+/// checks afterwards that the size and modification time haven't changed, which catches the
+/// writers that don't bother with locks. Anything that can't be mapped, such as a pipe or an
+/// empty file, is read into memory instead; so is standard input, given [`STDIN`] as the path.
+/// A gzip or zstd compressed input is decompressed into memory.
+pub struct Input {
+    data: Data,
+    source: Option<Source>,
+}
+
+impl Input {
+    /// Opens `path`, mapping it if possible, and decompressing it on every CPU if need be.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with(path, true, available_cpus())
+    }
+
+    /// Opens `path`, reading it into memory rather than mapping it if `map` is false. A
+    /// compressed input is decompressed with up to `threads` threads.
+    pub fn open_with(path: impl AsRef<Path>, map: bool, threads: usize) -> io::Result<Self> {
+        let (data, source) = if path.as_ref() == Path::new(STDIN) {
+            let mut buffer = Vec::new();
+            io::stdin().lock().read_to_end(&mut buffer)?;
+            (Data::Buffered(buffer), None)
+        } else {
+            let source = Source::open(path.as_ref())?;
+            let data = if source.is_file {
+                source.read(0, source.len as usize, map)?
+            } else {
+                let mut buffer = Vec::new();
+                (&source.file).read_to_end(&mut buffer)?;
+                Data::Buffered(buffer)
+            };
+            (data, Some(source))
+        };
+        let data = match Compression::detect(&data) {
+            Some(compression) => Data::Buffered(decompress(data, compression, threads)?),
+            None => data,
+        };
+        Ok(Input { data, source })
+    }
+
+    /// Was the input mapped, rather than read into memory?
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.data, Data::Mapped(_))
+    }
+
+    /// Checks that the file hasn't been changed since it was opened, which would mean the
+    /// results can't be trusted. There's nothing to check for standard input.
+    pub fn verify(&self) -> io::Result<()> {
+        self.source.as_ref().map_or(Ok(()), Source::verify)
+    }
+}
+
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Reads a file a window at a time, so only about `window_size` bytes of it are mapped (or
+/// buffered) at once, however big the file is.
+///
+/// Each window ends just after a newline, so no record is split between two of them; a record
+/// longer than the window makes that one window bigger. A window's pages are released when
+/// it's dropped. The file is locked and checked the same way as [`Input`].
+pub struct Windows {
+    source: Source,
+    window_size: usize,
+    map: bool,
+    next: u64,
+}
+
+impl Windows {
+    pub fn open(path: impl AsRef<Path>, window_size: usize, map: bool) -> io::Result<Self> {
+        let source = Source::open(path.as_ref())?;
+        if !source.is_file {
+            return Err(io::Error::other(format!("{} can't be read in windows", source.path.display())));
+        }
+        Ok(Windows {
+            source,
+            window_size: window_size.max(1),
+            map,
+            next: 0,
+        })
+    }
+
+    /// See [`Input::verify`].
+    pub fn verify(&self) -> io::Result<()> {
+        self.source.verify()
+    }
+}
+
+impl Iterator for Windows {
+    type Item = io::Result<Window>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next;
+        let remaining = self.source.len.checked_sub(offset).filter(|&remaining| remaining > 0)?;
+        let mut size = self.window_size;
+        loop {
+            let size_here = u64::min(size as u64, remaining) as usize;
+            let data = match self.source.read(offset, size_here, self.map) {
+                Ok(data) => data,
+                Err(e) => {
+                    self.next = self.source.len;
+                    return Some(Err(e));
+                }
+            };
+            let end = if size_here as u64 == remaining {
+                Some(size_here)
+            } else {
+                data.iter().rposition(|&b| b == b'\n').map(|i| i + 1)
+            };
+            match end {
+                Some(end) => {
+                    self.next = offset + end as u64;
+                    return Some(Ok(Window { data, len: end, offset }));
+                }
+                // Not even one whole record: try again with more.
+                None => size = size.saturating_mul(2),
+            }
+        }
+    }
+}
+
+/// A record-aligned piece of a bigger input, such as a [`Window`].
+pub trait Piece: Deref<Target = [u8]> {
+    /// Where the piece starts in the input.
+    fn offset(&self) -> u64;
+}
+
+/// One record-aligned piece of the input; see [`Windows`].
+pub struct Window {
+    data: Data,
+    len: usize,
+    /// Where the window starts in the file.
+    pub offset: u64,
+}
+
+impl Piece for Window {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Deref for Window {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("brc-{name}-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn mapped_and_buffered_inputs_match_and_notice_changes() {
+        let path = temp_file("input", b"Oslo;1.0\n");
+        let mapped = Input::open(&path).unwrap();
+        let buffered = Input::open_with(&path, false, 1).unwrap();
+        assert!(mapped.is_mapped() && !buffered.is_mapped());
+        assert_eq!(&*mapped, &*buffered);
+        assert!(buffered.verify().is_ok());
+
+        drop(mapped);
+        File::options().append(true).open(&path).unwrap().write_all(b"Lima;2.0\n").unwrap();
+        assert!(buffered.verify().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn directories_and_globs_expand_to_sorted_files() {
+        let dir = std::env::temp_dir().join(format!("brc-expand-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        for name in ["b.txt", "a.txt", "c.csv", ".hidden"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let dir_name = dir.to_str().unwrap().to_string();
+        let expanded = expand(&[dir_name.clone(), format!("{dir_name}/*.txt"), STDIN.to_string()]).unwrap();
+        let names: Vec<_> = expanded.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["a.txt", "b.txt", "c.csv", STDIN]);
+        assert!(expand(&[format!("{dir_name}/*.zst")]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn windows_end_on_records_and_cover_the_file() {
+        let contents = b"Oslo;1.0\nSan Francisco;2.0\nLima;3.0\nRome;4.0";
+        let path = temp_file("windows", contents);
+        for map in [true, false] {
+            for window_size in [1, 5, 9, 12, 64] {
+                let mut next = 0;
+                for window in Windows::open(&path, window_size, map).unwrap() {
+                    let window = window.unwrap();
+                    assert_eq!(window.offset, next);
+                    assert_eq!(&*window, &contents[next as usize..next as usize + window.len()]);
+                    next += window.len() as u64;
+                    assert!(next == contents.len() as u64 || window.ends_with(b"\n"));
+                }
+                assert_eq!(next, contents.len() as u64, "window size {window_size}");
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,26 @@
+//! Shared building blocks for the billion row challenge variants.
+//!
+//! Each workshop step started life as a copy-and-paste of the previous one. The pieces that
+//! more than one step needs to agree on live here instead, so a fix lands everywhere at once.
+
+pub mod ascii;
+pub mod blocks;
+pub mod chunks;
+pub mod cli;
+pub mod compressed;
+pub mod error;
+pub mod export;
+pub mod histogram;
+pub mod input;
+pub mod lines;
+pub mod mapping;
+pub mod ranking;
+pub mod rollup;
+pub mod scan;
+pub mod schema;
+pub mod sketch;
+pub mod spill;
+pub mod stats;
+pub mod threads;
+pub mod timeseries;
+pub mod validate;
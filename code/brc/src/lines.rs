@@ -0,0 +1,105 @@
+//! Line-at-a-time reading for the `BufRead` based readers, keeping track of where we are.
+
+use std::io::{self, BufRead};
+use crate::error::{ParseError, ParseErrorKind};
+use crate::schema::Schema;
+use crate::scan::{clean_line, Record};
+
+/// A line of input, without its line ending.
+pub struct Line {
+    /// Lossily converted if it isn't valid UTF-8, in which case [`Line::record`] reports it.
+    pub text: String,
+    /// Byte offset of the start of the line.
+    pub offset: u64,
+    /// 1-based line number.
+    pub number: u64,
+    /// Set if the line isn't valid UTF-8, or in strict mode if it had a CRLF ending, a byte
+    /// order mark or nothing at all.
+    problem: Option<ParseErrorKind>,
+    schema: Schema,
+}
+
+impl Line {
+    /// Splits the line into station and temperature.
+    pub fn record(&self) -> Result<Record<'_>, ParseError> {
+        if let Some(kind) = self.problem {
+            return Err(ParseError::new(kind, self.offset, self.number, self.text.as_bytes()));
+        }
+        Record::split(&self.text, self.offset, self.number, &self.schema)
+    }
+}
+
+/// Like [`BufRead::lines`], but yields the position of each line too, and doesn't give up on
+/// invalid UTF-8, so a bad line can be reported precisely instead of aborting the whole read.
+///
+/// Line endings, byte order marks and blank lines are handled the same way as
+/// [`crate::scan::Records`], as is the header row if the [`Schema`] has one.
+pub struct Lines<R> {
+    reader: R,
+    offset: u64,
+    number: u64,
+    strict: bool,
+    schema: Schema,
+}
+
+impl<R: BufRead> Lines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            number: 0,
+            strict: false,
+            schema: Schema::CHALLENGE,
+        }
+    }
+
+    /// Report CRLF endings, byte order marks and blank lines instead of tolerating them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Read records laid out according to `schema`, rather than `station;temperature`.
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut text = Vec::new();
+            let length = match self.reader.read_until(b'\n', &mut text) {
+                Ok(0) => return None,
+                Ok(length) => length,
+                Err(e) => return Some(Err(e)),
+            };
+            let offset = self.offset;
+            self.offset += length as u64;
+            self.number += 1;
+            if text.last() == Some(&b'\n') {
+                text.pop();
+            }
+
+            let mut problem = match clean_line(&text, offset == 0, self.strict) {
+                Ok(Some(_)) if offset == 0 && self.schema.header => continue,
+                Ok(Some(range)) => {
+                    text.truncate(range.end);
+                    text.drain(..range.start);
+                    None
+                }
+                Ok(None) => continue,
+                Err(kind) => Some(kind),
+            };
+            // Checked once here, so nothing downstream needs to check the station or temperature.
+            let text = String::from_utf8(text).unwrap_or_else(|e| {
+                problem = problem.or(Some(ParseErrorKind::InvalidUtf8));
+                String::from_utf8_lossy(e.as_bytes()).into_owned()
+            });
+            return Some(Ok(Line { text, offset, number: self.number, problem, schema: self.schema }));
+        }
+    }
+}
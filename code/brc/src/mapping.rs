@@ -0,0 +1,180 @@
+//! Helping the kernel page a memory-mapped input in, and seeing how well it went.
+
+use std::fmt;
+use std::hint::black_box;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use clap::ValueEnum;
+use crate::chunks::ChunkQueue;
+
+/// A hint passed to `madvise` for the whole mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Advice {
+    /// We'll read front to back, so read ahead aggressively and drop pages behind us.
+    Sequential,
+    /// We'll need all of it soon, so start reading it in now.
+    Willneed,
+    /// Back the mapping with huge pages, where the kernel and filesystem support it.
+    Hugepage,
+}
+
+impl Advice {
+    #[cfg(unix)]
+    fn flag(self) -> libc::c_int {
+        match self {
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+            Advice::Willneed => libc::MADV_WILLNEED,
+            #[cfg(target_os = "linux")]
+            Advice::Hugepage => libc::MADV_HUGEPAGE,
+            #[cfg(not(target_os = "linux"))]
+            Advice::Hugepage => libc::MADV_NORMAL,
+        }
+    }
+}
+
+/// Passes `advice` to the kernel for `buffer`. `madvise` wants a page-aligned start, so a
+/// partial first page is left out. Does nothing on platforms without `madvise`.
+pub fn advise(buffer: &[u8], advice: Advice) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let skip = buffer.as_ptr().align_offset(page_size);
+        if skip >= buffer.len() {
+            return Ok(());
+        }
+        let buffer = &buffer[skip..];
+        // SAFETY: madvise only changes how the kernel pages the range in; it doesn't change
+        // what's in it, and these hints never discard anything.
+        let result = unsafe { libc::madvise(buffer.as_ptr() as *mut libc::c_void, buffer.len(), advice.flag()) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (buffer, advice);
+    Ok(())
+}
+
+/// Touching one byte in each page is enough to fault it in; pages are never smaller than this.
+const PAGE_SIZE: usize = 4096;
+
+/// Faults in the pages of each chunk in turn, staying no more than `ahead` chunks in front of
+/// the chunk the workers last claimed, so the prefetched pages are still resident when they
+/// get there. Runs until every chunk has been touched or `stop` is set.
+///
+/// While it's far enough ahead it polls the queue every 100µs rather than blocking on it.
+/// Chunks take milliseconds to read, so the polling costs next to nothing, whereas waking it
+/// up properly would mean a lock or a condvar on every claim, in the workers' hot loop. It
+/// can't wait forever either: the workers claim every chunk before they finish, even after a
+/// failure (see [`ChunkQueue::cancel`]), and then there's nothing left to wait for.
+pub fn prefetch(buffer: &[u8], queue: &ChunkQueue, ahead: usize, stop: &AtomicBool) {
+    for index in 0..queue.len() {
+        while index >= queue.claimed() + ahead {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut sum = 0u8;
+        for offset in queue.chunk(index).step_by(PAGE_SIZE) {
+            sum = sum.wrapping_add(buffer[offset]);
+        }
+        black_box(sum);
+    }
+}
+
+/// The process's page-fault counters, to see what the hints above actually did.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageFaults {
+    /// Faults that had to wait for the disk.
+    pub major: u64,
+    /// Faults satisfied from the page cache.
+    pub minor: u64,
+}
+
+impl PageFaults {
+    /// The counts so far, or zero where `getrusage` isn't available.
+    pub fn now() -> Self {
+        #[cfg(unix)]
+        {
+            // SAFETY: getrusage only writes to the struct we hand it.
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } == 0 {
+                return PageFaults {
+                    major: usage.ru_majflt as u64,
+                    minor: usage.ru_minflt as u64,
+                };
+            }
+        }
+        PageFaults::default()
+    }
+
+    /// The faults taken since `earlier`.
+    pub fn since(earlier: PageFaults) -> Self {
+        let now = Self::now();
+        PageFaults {
+            major: now.major.saturating_sub(earlier.major),
+            minor: now.minor.saturating_sub(earlier.minor),
+        }
+    }
+}
+
+impl fmt::Display for PageFaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} major, {} minor", self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::chunks::Scheduler;
+
+    #[test]
+    fn prefetch_stops_when_asked() {
+        let buffer = "a;1.0\n".repeat(1000).into_bytes();
+        let queue = ChunkQueue::new(&buffer, 1, Scheduler::Stealing, 100);
+        let stop = AtomicBool::new(false);
+        // Nothing is ever claimed, so it would wait for ever if it didn't look at `stop`
+        thread::scope(|scope| {
+            let prefetcher = scope.spawn(|| prefetch(&buffer, &queue, 1, &stop));
+            thread::sleep(Duration::from_millis(10));
+            stop.store(true, Ordering::Relaxed);
+            prefetcher.join().unwrap();
+        });
+        assert_eq!(queue.claimed(), 0);
+    }
+
+    #[test]
+    fn prefetch_finishes_when_the_queue_is_drained() {
+        let buffer = "a;1.0\n".repeat(1000).into_bytes();
+        let queue = ChunkQueue::new(&buffer, 1, Scheduler::Stealing, 100);
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let prefetcher = scope.spawn(|| prefetch(&buffer, &queue, 0, &stop));
+            assert_eq!(queue.worker(0).count(), queue.len());
+            prefetcher.join().unwrap();
+        });
+        // Cancelling drains the queue too
+        let queue = ChunkQueue::new(&buffer, 1, Scheduler::Stealing, 100);
+        queue.cancel();
+        prefetch(&buffer, &queue, 0, &stop);
+    }
+
+    #[test]
+    fn advice_on_awkward_buffers() {
+        let buffer = vec![0u8; 3 * PAGE_SIZE];
+        for advice in [Advice::Sequential, Advice::Willneed] {
+            advise(&[], advice).unwrap();
+            advise(&buffer[1..10], advice).unwrap();
+            advise(&buffer[1..], advice).unwrap();
+            advise(&buffer[PAGE_SIZE - 1..PAGE_SIZE + 1], advice).unwrap();
+        }
+    }
+}
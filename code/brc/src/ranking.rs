@@ -0,0 +1,131 @@
+//! Putting stations in order, to answer questions like "which ten stations were hottest?".
+
+use std::cmp::Ordering;
+use clap::ValueEnum;
+use crate::ascii::Scale;
+use crate::stats::Accumulator;
+
+/// What to rank stations by. Whatever it is, stations that tie are put in order of name, so the
+/// ranking comes out the same every time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RankBy {
+    /// Highest mean first.
+    Hottest,
+    /// Lowest mean first.
+    Coldest,
+    /// Biggest difference between the highest and lowest reading first.
+    Range,
+    /// Most widely spread readings first.
+    Variance,
+    /// Most readings first.
+    MostReadings,
+    /// Fewest readings first.
+    FewestReadings,
+}
+
+impl RankBy {
+    /// Whether `a` comes before `b`. Neither may be empty.
+    pub fn compare(self, a: &Accumulator, b: &Accumulator) -> Ordering {
+        match self {
+            RankBy::Hottest => compare_means(b, a),
+            RankBy::Coldest => compare_means(a, b),
+            RankBy::Range => range(b).cmp(&range(a)),
+            // Scaling doesn't change the order, so any scale will do
+            RankBy::Variance => b.variance(Scale::TENTHS).total_cmp(&a.variance(Scale::TENTHS)),
+            RankBy::MostReadings => b.count.cmp(&a.count),
+            RankBy::FewestReadings => a.count.cmp(&b.count),
+        }
+    }
+
+    /// The value a station was ranked by, ready to print at `scale`.
+    pub fn measure(self, readings: &Accumulator, scale: Scale) -> String {
+        match self {
+            RankBy::Hottest | RankBy::Coldest => scale.format(readings.mean()).to_string(),
+            RankBy::Range => scale.format(range(readings)).to_string(),
+            RankBy::Variance => {
+                let spread = readings.spread(scale);
+                format!("{:.*}", spread.digits, spread.variance)
+            }
+            RankBy::MostReadings | RankBy::FewestReadings => readings.count.to_string(),
+        }
+    }
+}
+
+/// Compares the exact means, rather than the rounded ones, so that only true ties are left
+/// for the names to settle.
+fn compare_means(a: &Accumulator, b: &Accumulator) -> Ordering {
+    (a.sum as i128 * b.count as i128).cmp(&(b.sum as i128 * a.count as i128))
+}
+
+fn range(readings: &Accumulator) -> i64 {
+    readings.max as i64 - readings.min as i64
+}
+
+/// Sorts `stations` by `by`, or by name if that's `None`, and keeps only the first `top` of
+/// them if there's a limit. `station` picks out each one's name and readings.
+pub fn rank<T>(stations: &mut Vec<T>, by: Option<RankBy>, top: Option<usize>, station: impl Fn(&T) -> (&str, &Accumulator)) {
+    let compare = |a: &T, b: &T| {
+        let ((a_name, a), (b_name, b)) = (station(a), station(b));
+        by.map_or(Ordering::Equal, |by| by.compare(a, b)).then_with(|| a_name.cmp(b_name))
+    };
+    if let Some(top) = top.filter(|&top| top < stations.len()) {
+        // Only the ones that make the cut need sorting
+        if top > 0 {
+            stations.select_nth_unstable_by(top - 1, compare);
+        }
+        stations.truncate(top);
+    }
+    stations.sort_unstable_by(compare);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stations() -> Vec<(&'static str, Accumulator)> {
+        [
+            ("Oslo", &[-50, 10][..]),
+            ("Cairo", &[300, 200]),
+            ("Lima", &[180, 190, 200]),
+            ("Abha", &[250]),
+            ("Baku", &[240, 260]),
+            ("Nuuk", &[-100, -120, -80, -60]),
+        ]
+        .into_iter()
+        .map(|(name, readings)| {
+            let mut accumulator = Accumulator::default();
+            readings.iter().for_each(|&value| accumulator.add(value));
+            (name, accumulator)
+        })
+        .collect()
+    }
+
+    fn ranked(by: Option<RankBy>, top: Option<usize>) -> Vec<&'static str> {
+        let mut stations = stations();
+        rank(&mut stations, by, top, |(name, readings)| (name, readings));
+        stations.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn rankings() {
+        assert_eq!(ranked(None, None), ["Abha", "Baku", "Cairo", "Lima", "Nuuk", "Oslo"]);
+        // Abha and Baku both average 25.0, and Abha comes first by name
+        assert_eq!(ranked(Some(RankBy::Hottest), Some(3)), ["Abha", "Baku", "Cairo"]);
+        assert_eq!(ranked(Some(RankBy::Coldest), Some(2)), ["Nuuk", "Oslo"]);
+        assert_eq!(ranked(Some(RankBy::Range), Some(2)), ["Cairo", "Nuuk"]);
+        assert_eq!(ranked(Some(RankBy::Variance), Some(1)), ["Cairo"]);
+        assert_eq!(ranked(Some(RankBy::MostReadings), None), ["Nuuk", "Lima", "Baku", "Cairo", "Oslo", "Abha"]);
+        assert_eq!(ranked(Some(RankBy::FewestReadings), Some(0)), Vec::<&str>::new());
+        assert_eq!(ranked(Some(RankBy::FewestReadings), Some(10)), ["Abha", "Baku", "Cairo", "Oslo", "Lima", "Nuuk"]);
+    }
+
+    #[test]
+    fn measures() {
+        let stations = stations();
+        let ((_, oslo), (_, nuuk)) = (&stations[0], &stations[5]);
+        assert_eq!(RankBy::Coldest.measure(nuuk, Scale::TENTHS), "-9.0");
+        assert_eq!(RankBy::Range.measure(nuuk, Scale::TENTHS), "6.0");
+        assert_eq!(RankBy::FewestReadings.measure(nuuk, Scale::TENTHS), "4");
+        assert_eq!(RankBy::Variance.measure(oslo, Scale::TENTHS), "9.0");
+    }
+}
@@ -0,0 +1,174 @@
+//! Adding stations up by region and by country, from a table saying where each one is.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use crate::schema::Fields;
+use crate::stats::Accumulator;
+
+/// What stations missing from the table are grouped under.
+pub const UNMAPPED: &str = "unmapped";
+
+/// Which region and country each station is in.
+#[derive(Debug, Clone, Default)]
+pub struct Regions {
+    stations: HashMap<String, (String, String)>,
+}
+
+impl Regions {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a table of `station;region;country` lines. Fields may be quoted with `"`, and
+    /// blank lines, `#` comments and a `station;region;country` header are skipped. A station
+    /// can't be in two places at once.
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut regions = Regions::default();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+            let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {message}", index + 1));
+            if line.trim().is_empty() || line.starts_with('#') || (index == 0 && line == "station;region;country") {
+                continue;
+            }
+            let fields = Fields::new(line, b';', Some(b'"'))
+                .map(|field| field.map(|field| field.unescaped(Some(b'"')).into_owned()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|kind| invalid(kind.to_string()))?;
+            let [station, region, country] = <[String; 3]>::try_from(fields)
+                .map_err(|fields| invalid(format!("expected station;region;country, not {} field(s)", fields.len())))?;
+            match regions.stations.get(&station) {
+                Some(place) if *place != (region.clone(), country.clone()) => {
+                    return Err(invalid(format!("{station} is already in {}/{}", place.1, place.0)));
+                }
+                Some(_) => {}
+                None => {
+                    regions.stations.insert(station, (region, country));
+                }
+            }
+        }
+        Ok(regions)
+    }
+
+    /// Merges each station's readings into its region's and its country's. All in one go, so
+    /// the input is only read the once.
+    pub fn roll_up<'a>(&self, stations: impl IntoIterator<Item = (&'a str, &'a Accumulator)>) -> RollUp {
+        let mut rollup = RollUp::default();
+        for (station, readings) in stations {
+            match self.stations.get(station) {
+                Some((region, country)) => {
+                    add(&mut rollup.regions, format!("{country}/{region}"), readings);
+                    add(&mut rollup.countries, country.clone(), readings);
+                }
+                None => {
+                    match &mut rollup.unmapped {
+                        Some(unmapped) => unmapped.merge(readings),
+                        None => rollup.unmapped = Some(readings.clone()),
+                    }
+                    rollup.unmapped_stations += 1;
+                }
+            }
+        }
+        rollup
+    }
+}
+
+fn add(groups: &mut BTreeMap<String, Accumulator>, group: String, readings: &Accumulator) {
+    match groups.get_mut(&group) {
+        Some(total) => total.merge(readings),
+        None => {
+            groups.insert(group, readings.clone());
+        }
+    }
+}
+
+/// The readings of every region and country, and of the stations that aren't in either.
+#[derive(Debug, Clone, Default)]
+pub struct RollUp {
+    /// By `country/region`, as the same region name might turn up in two countries.
+    regions: BTreeMap<String, Accumulator>,
+    countries: BTreeMap<String, Accumulator>,
+    unmapped: Option<Accumulator>,
+    unmapped_stations: usize,
+}
+
+impl RollUp {
+    /// Each region in order, as `country/region`, then the unmapped stations if there are any.
+    pub fn regions(&self) -> impl Iterator<Item = (&str, &Accumulator)> {
+        self.with_unmapped(&self.regions)
+    }
+
+    /// Each country in order, then the unmapped stations if there are any.
+    pub fn countries(&self) -> impl Iterator<Item = (&str, &Accumulator)> {
+        self.with_unmapped(&self.countries)
+    }
+
+    fn with_unmapped<'a>(&'a self, groups: &'a BTreeMap<String, Accumulator>) -> impl Iterator<Item = (&'a str, &'a Accumulator)> {
+        let unmapped = self.unmapped.iter().map(|readings| (UNMAPPED, readings));
+        groups.iter().map(|(group, readings)| (group.as_str(), readings)).chain(unmapped)
+    }
+
+    /// How many stations weren't in the table.
+    pub fn unmapped_stations(&self) -> usize {
+        self.unmapped_stations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accumulator(readings: &[i32]) -> Accumulator {
+        let mut accumulator = Accumulator::default();
+        readings.iter().for_each(|&value| accumulator.add(value));
+        accumulator
+    }
+
+    #[test]
+    fn stations_roll_up_into_regions_and_countries() {
+        let table = "station;region;country\n\
+                     # Germany\n\
+                     Berlin;Berlin;Germany\n\
+                     Munich;Bavaria;Germany\n\
+                     Nuremberg;Bavaria;Germany\n\
+                     \n\
+                     \"Frankfurt; am Main\";Hesse;Germany\n\
+                     Berlin;Berlin;Germany\n\
+                     Lyon;Auvergne-Rhône-Alpes;France\n";
+        let regions = Regions::read(table.as_bytes()).unwrap();
+        let stations = [
+            ("Berlin", accumulator(&[10, 20])),
+            ("Munich", accumulator(&[-50])),
+            ("Nuremberg", accumulator(&[30, 40])),
+            ("Frankfurt; am Main", accumulator(&[0])),
+            ("Lyon", accumulator(&[100])),
+            ("Atlantis", accumulator(&[7])),
+            ("El Dorado", accumulator(&[9, 11])),
+        ];
+        let rollup = regions.roll_up(stations.iter().map(|(station, readings)| (*station, readings)));
+        let summary = |groups: Vec<(&str, &Accumulator)>| {
+            groups.into_iter().map(|(group, readings)| (group.to_string(), readings.min, readings.max, readings.count)).collect::<Vec<_>>()
+        };
+        let expected = [
+            ("France/Auvergne-Rhône-Alpes", 100, 100, 1),
+            ("Germany/Bavaria", -50, 40, 3),
+            ("Germany/Berlin", 10, 20, 2),
+            ("Germany/Hesse", 0, 0, 1),
+            (UNMAPPED, 7, 11, 3),
+        ];
+        assert_eq!(summary(rollup.regions().collect()), expected.map(|(group, min, max, count)| (group.to_string(), min, max, count)));
+        let expected = [("France", 100, 100, 1), ("Germany", -50, 40, 6), (UNMAPPED, 7, 11, 3)];
+        assert_eq!(summary(rollup.countries().collect()), expected.map(|(group, min, max, count)| (group.to_string(), min, max, count)));
+        assert_eq!(rollup.unmapped_stations(), 2);
+    }
+
+    #[test]
+    fn bad_tables() {
+        let error = Regions::read("Berlin;Berlin\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "line 1: expected station;region;country, not 2 field(s)");
+        let error = Regions::read("Berlin;Berlin;Germany\nBerlin;Brandenburg;Germany\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: Berlin is already in Germany/Berlin");
+    }
+}
@@ -0,0 +1,346 @@
+//! Splitting a sea of bytes into `station;temperature` records.
+
+use std::borrow::Cow;
+use std::ops::Range;
+use crate::ascii::Scale;
+use crate::error::{ParseError, ParseErrorKind};
+use crate::schema::Schema;
+use crate::timeseries::parse_timestamp;
+use crate::validate::ValueFilter;
+
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+/// Tidies up a raw line (without its `\n`): drops the `\r` left over from a CRLF ending and, at
+/// the very start of the input, a UTF-8 byte order mark. Returns the part of `text` to keep, or
+/// `None` for a blank line that should be skipped. In strict mode each of these is an error
+/// instead.
+#[inline(always)]
+pub fn clean_line(text: &[u8], start_of_input: bool, strict: bool) -> Result<Option<Range<usize>>, ParseErrorKind> {
+    let mut range = 0 .. text.len();
+    if start_of_input && text.starts_with(BYTE_ORDER_MARK) {
+        if strict {
+            return Err(ParseErrorKind::ByteOrderMark);
+        }
+        range.start = BYTE_ORDER_MARK.len();
+    }
+    if text[range.clone()].ends_with(b"\r") {
+        if strict {
+            return Err(ParseErrorKind::CarriageReturn);
+        }
+        range.end -= 1;
+    }
+    if range.is_empty() {
+        if strict {
+            return Err(ParseErrorKind::BlankLine);
+        }
+        return Ok(None);
+    }
+    Ok(Some(range))
+}
+
+/// One `station;temperature` line. The line has already been checked for valid UTF-8, so
+/// both halves are ready to use as strings without checking them again.
+pub struct Record<'a> {
+    /// Only owned if it was quoted and had quotes to unescape.
+    pub station: Cow<'a, str>,
+    pub value: &'a str,
+    /// When the reading was taken, if the schema has a time column.
+    pub time: Option<&'a str>,
+    text: &'a str,
+    offset: u64,
+    line: u64,
+}
+
+impl<'a> Record<'a> {
+    /// Splits a line (without its `\n`) according to `schema`.
+    ///
+    /// For the challenge layout we just look for the last `;`. Temperatures never contain one,
+    /// so searching from the end only touches a handful of bytes.
+    #[inline(always)]
+    pub fn split(text: &'a str, offset: u64, line: u64, schema: &Schema) -> Result<Self, ParseError> {
+        let split = if schema.is_challenge() {
+            text.as_bytes()
+                .iter()
+                .rposition(|&b| b == b';')
+                .map(|delimiter| (Cow::Borrowed(&text[..delimiter]), &text[delimiter + 1..], None))
+                .ok_or(ParseErrorKind::MissingDelimiter(b';'))
+        } else {
+            schema.split(text)
+        };
+        match split {
+            Ok((station, value, time)) => Ok(Record { station, value, time, text, offset, line }),
+            Err(kind) => Err(ParseError::new(kind, offset, line, text.as_bytes())),
+        }
+    }
+
+    /// Builds an error pointing at this line.
+    pub fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(kind, self.offset, self.line, self.text.as_bytes())
+    }
+
+    /// The temperature, parsed as a float.
+    pub fn value_f32(&self) -> Result<f32, ParseError> {
+        self.value.parse::<f32>().map_err(|_| self.error(ParseErrorKind::InvalidNumber))
+    }
+
+    /// The temperature, parsed as a fixed-point number at `scale`.
+    #[inline(always)]
+    pub fn value_fixed(&self, scale: Scale) -> Result<i32, ParseError> {
+        scale.parse(self.value.as_bytes()).map_err(|kind| self.error(kind))
+    }
+
+    /// When the reading was taken, in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> Result<i64, ParseError> {
+        parse_timestamp(self.time.unwrap_or_default()).map_err(|kind| self.error(kind))
+    }
+
+    /// The temperature, parsed as a fixed-point number at `scale` and run through `filter`.
+    /// `None` means it was quarantined.
+    #[inline(always)]
+    pub fn value_checked(&self, scale: Scale, filter: &ValueFilter) -> Result<Option<i32>, ParseError> {
+        match scale.parse(self.value.as_bytes()) {
+            Ok(value) => filter.check(value, scale),
+            // NaN, infinity and the likes of 1e9 aren't garbage, they're readings the filter
+            // gets to judge
+            Err(kind) => match self.value.parse::<f32>() {
+                Ok(value) if !value.is_finite() => filter.non_finite(),
+                Ok(value) => filter.check_float(value, kind),
+                Err(_) => Err(kind),
+            },
+        }
+        .map_err(|kind| self.error(kind))
+    }
+}
+
+/// How much of the input to check for valid UTF-8 at a time. Small enough that the lines are
+/// still in cache when we come to split them.
+const VALIDATION_BLOCK: usize = 256 * 1024;
+
+/// Iterates the records in a chunk of the input.
+///
+/// Line numbers count from the start of the chunk, and offsets from the start of the input
+/// (the chunk starts `base_offset` bytes in). A worker that only sees part of the file can't
+/// know how many lines came before it, so callers fix those up afterwards using
+/// [`Records::lines`] from the earlier chunks.
+///
+/// CRLF endings, a leading byte order mark and blank lines are tolerated unless
+/// [`Records::strict`] is set; see [`clean_line`]. If the [`Schema`] has a header, the first
+/// line of the input is skipped.
+///
+/// Rather than checking every station and temperature for valid UTF-8, we check the buffer a
+/// block at a time as we reach it and slice lines out of the checked `&str`. Every delimiter
+/// is ASCII, which can never appear inside a multi-byte character, so those slices are always
+/// valid too. A line that fails the check is reported as [`ParseErrorKind::InvalidUtf8`] and
+/// checking picks up again at the next line.
+pub struct Records<'a> {
+    buffer: &'a [u8],
+    base_offset: u64,
+    index: usize,
+    line: u64,
+    strict: bool,
+    schema: Schema,
+    /// The most recently validated stretch of `buffer`, starting at `validated_from`.
+    validated: &'a str,
+    validated_from: usize,
+}
+
+impl<'a> Records<'a> {
+    pub fn new(buffer: &'a [u8], base_offset: u64) -> Self {
+        Self {
+            buffer,
+            base_offset,
+            index: 0,
+            line: 0,
+            strict: false,
+            schema: Schema::CHALLENGE,
+            validated: "",
+            validated_from: 0,
+        }
+    }
+
+    /// Report CRLF endings, byte order marks and blank lines instead of tolerating them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Read records laid out according to `schema`, rather than `station;temperature`.
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// How many lines have been read so far.
+    pub fn lines(&self) -> u64 {
+        self.line
+    }
+
+    /// The line `start..end` as a `&str`, validating the next block of the buffer if we haven't
+    /// got that far yet. `None` if the line isn't valid UTF-8.
+    #[inline(always)]
+    fn line_str(&mut self, start: usize, end: usize) -> Option<&'a str> {
+        if start < self.validated_from || end > self.validated_from + self.validated.len() {
+            // Always start at a line boundary, so a block never begins part way into a
+            // character. It may end part way into one, but only after this line.
+            let block_end = usize::max(end, usize::min(start + VALIDATION_BLOCK, self.buffer.len()));
+            let block = &self.buffer[start .. block_end];
+            self.validated_from = start;
+            self.validated = match std::str::from_utf8(block) {
+                Ok(valid) => valid,
+                Err(e) => std::str::from_utf8(&block[.. e.valid_up_to()]).unwrap(),
+            };
+            if end > start + self.validated.len() {
+                return None;
+            }
+        }
+        let from = self.validated_from;
+        Some(&self.validated[start - from .. end - from])
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, ParseError>;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.index >= self.buffer.len() {
+                return None;
+            }
+            let start = self.index;
+            let end = self.buffer[start..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(self.buffer.len(), |i| start + i);
+            self.index = end + 1;
+            self.line += 1;
+
+            let offset = self.base_offset + start as u64;
+            let Some(text) = self.line_str(start, end) else {
+                let text = &self.buffer[start..end];
+                return Some(Err(ParseError::new(ParseErrorKind::InvalidUtf8, offset, self.line, text)));
+            };
+            match clean_line(text.as_bytes(), offset == 0, self.strict) {
+                Ok(Some(_)) if offset == 0 && self.schema.header => continue,
+                Ok(Some(range)) => return Some(Record::split(&text[range], offset, self.line, &self.schema)),
+                Ok(None) => continue,
+                Err(kind) => return Some(Err(ParseError::new(kind, offset, self.line, text.as_bytes()))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ErrorPolicy, ErrorReport};
+
+    const INPUT: &[u8] = b"Oslo;1.0\nbad line\nLima;2.0\nRome;x\nOslo;3.0\nParis\n";
+
+    fn scan(chunk: &[u8], base_offset: u64, errors: &mut ErrorReport) -> u64 {
+        let mut records = Records::new(chunk, base_offset);
+        for record in records.by_ref() {
+            if let Err(error) = record.and_then(|record| record.value_fixed(Scale::TENTHS)) {
+                errors.record(error).unwrap();
+            }
+        }
+        records.lines()
+    }
+
+    #[test]
+    fn chunked_scan_reports_the_same_positions_as_a_single_pass() {
+        let mut expected = ErrorReport::new(ErrorPolicy::Collect(10));
+        assert_eq!(scan(INPUT, 0, &mut expected), 6);
+        let positions: Vec<_> = expected.errors().iter().map(|e| (e.line, e.offset, e.kind)).collect();
+        assert_eq!(positions, [
+            (2, 9, ParseErrorKind::MissingDelimiter(b';')),
+            (4, 27, ParseErrorKind::InvalidByte(b'x')),
+            (6, 43, ParseErrorKind::MissingDelimiter(b';')),
+        ]);
+
+        // Split after every line in turn, as the threaded readers would.
+        let boundaries = INPUT.iter().enumerate().filter(|(_, &b)| b == b'\n').map(|(i, _)| i + 1);
+        for split in boundaries {
+            let mut merged = ErrorReport::new(ErrorPolicy::Collect(10));
+            let mut lines_before = 0;
+            for (start, end) in [(0, split), (split, INPUT.len())] {
+                let mut local = ErrorReport::new(ErrorPolicy::Collect(10));
+                let lines = scan(&INPUT[start..end], start as u64, &mut local);
+                merged.merge(local, lines_before);
+                lines_before += lines;
+            }
+            assert_eq!(merged.errors(), expected.errors(), "split at {split}");
+            assert_eq!(merged.skipped(), 3);
+        }
+    }
+
+    #[test]
+    fn crlf_bom_and_blank_lines() {
+        let input = b"\xEF\xBB\xBFOslo;1.0\r\n\nLima;2.0\r\n\r\nRome;-3.5";
+        let lenient: Vec<_> = Records::new(input, 0)
+            .map(|record| {
+                let record = record.unwrap();
+                (record.station.to_string(), record.value_fixed(Scale::TENTHS).unwrap())
+            })
+            .collect();
+        assert_eq!(lenient, [("Oslo".to_string(), 10), ("Lima".to_string(), 20), ("Rome".to_string(), -35)]);
+
+        let strict: Vec<_> = Records::new(input, 0)
+            .strict(true)
+            .filter_map(|record| record.err().map(|e| (e.line, e.kind)))
+            .collect();
+        assert_eq!(strict, [
+            (1, ParseErrorKind::ByteOrderMark),
+            (2, ParseErrorKind::BlankLine),
+            (3, ParseErrorKind::CarriageReturn),
+            (4, ParseErrorKind::CarriageReturn),
+        ]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_on_its_own_line() {
+        let input = "Oslo;1.0\nSão Paulo;2.0\nLima;3.0\n".as_bytes().to_vec();
+        let mut broken = input.clone();
+        broken[11] = 0xFF; // The second byte of the 'ã'
+        let stations: Vec<_> = Records::new(&broken, 0)
+            .map(|record| record.map(|record| record.station.to_string()).map_err(|e| (e.line, e.offset, e.kind)))
+            .collect();
+        assert_eq!(stations, [
+            Ok("Oslo".to_string()),
+            Err((2, 9, ParseErrorKind::InvalidUtf8)),
+            Ok("Lima".to_string()),
+        ]);
+
+        // Lines split across validation blocks come out whole.
+        let many = input.repeat(VALIDATION_BLOCK / input.len() + 2);
+        assert!(Records::new(&many, 0).all(|record| record.is_ok_and(|record| record.value_fixed(Scale::TENTHS).is_ok())));
+    }
+
+    #[test]
+    fn values_without_a_fixed_point_form_still_go_through_the_filter() {
+        use crate::validate::{InvalidValueAction, ValueFilter};
+        let check = |text: &str, action| {
+            let filter = ValueFilter { min: None, max: Some(100.0), action };
+            let record = Records::new(text.as_bytes(), 0).next().unwrap().unwrap();
+            record.value_checked(Scale::TENTHS, &filter).map_err(|e| e.kind)
+        };
+        assert_eq!(check("A;1.0", InvalidValueAction::Reject), Ok(Some(10)));
+        assert_eq!(check("B;1e9", InvalidValueAction::Reject), Err(ParseErrorKind::OutOfRange));
+        assert_eq!(check("B;1e9", InvalidValueAction::Quarantine), Ok(None));
+        assert_eq!(check("C;NaN", InvalidValueAction::Quarantine), Ok(None));
+        // Within the limits, but still not something we can represent
+        assert_eq!(check("D;1e1", InvalidValueAction::Quarantine), Err(ParseErrorKind::InvalidByte(b'e')));
+        assert_eq!(check("E;1.25", InvalidValueAction::Quarantine), Err(ParseErrorKind::TooManyFractionDigits));
+    }
+
+    #[test]
+    fn collect_keeps_only_the_first_errors_in_file_order() {
+        let mut first = ErrorReport::new(ErrorPolicy::Collect(2));
+        scan(&INPUT[..18], 0, &mut first);
+        let mut second = ErrorReport::new(ErrorPolicy::Collect(2));
+        scan(&INPUT[18..], 18, &mut second);
+        first.merge(second, 2);
+        assert_eq!(first.skipped(), 3);
+        assert_eq!(first.errors().iter().map(|e| e.line).collect::<Vec<_>>(), [2, 4]);
+    }
+}
@@ -0,0 +1,283 @@
+//! Describing where the station, temperature and any timestamp live in each line.
+
+use std::borrow::Cow;
+use std::str::FromStr;
+use crate::error::ParseErrorKind;
+
+/// The layout of a record. The default is the challenge's `station;temperature`, which gets a
+/// dedicated fast path; anything else is split field by field.
+///
+/// Quoted fields may contain the delimiter, and a doubled quote inside one stands for a single
+/// quote character. Fields can't span lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schema {
+    pub delimiter: u8,
+    /// 0-based column holding the station name.
+    pub station_column: usize,
+    /// 0-based column holding the temperature.
+    pub value_column: usize,
+    /// 0-based column holding when the reading was taken, if we need it.
+    pub time_column: Option<usize>,
+    /// Skip the first line of the input.
+    pub header: bool,
+    pub quote: Option<u8>,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Self::CHALLENGE
+    }
+}
+
+impl Schema {
+    /// `station;temperature`, no header, no quoting.
+    pub const CHALLENGE: Schema = Schema {
+        delimiter: b';',
+        station_column: 0,
+        value_column: 1,
+        time_column: None,
+        header: false,
+        quote: None,
+    };
+
+    /// Can we take the fast path?
+    #[inline(always)]
+    pub fn is_challenge(&self) -> bool {
+        *self == Self::CHALLENGE
+    }
+
+    /// Pulls the station, temperature and timestamp (if there's a time column) out of a line.
+    pub fn split<'a>(&self, text: &'a str) -> Result<(Cow<'a, str>, &'a str, Option<&'a str>), ParseErrorKind> {
+        let mut station = None;
+        let mut value = None;
+        let mut time = None;
+        let mut columns = 0;
+        for (column, field) in Fields::new(text, self.delimiter, self.quote).enumerate() {
+            let field = field?;
+            columns = column + 1;
+            if column == self.station_column {
+                station = Some(field.unescaped(self.quote));
+            }
+            if column == self.value_column {
+                value = Some(field.text);
+            }
+            if Some(column) == self.time_column {
+                time = Some(field.text);
+            }
+            if station.is_some() && value.is_some() && time.is_some() == self.time_column.is_some() {
+                break;
+            }
+        }
+        match (station, value, self.time_column) {
+            // A line that doesn't split at all was probably written with another delimiter
+            (None, _, _) | (_, None, _) if columns == 1 => Err(ParseErrorKind::MissingDelimiter(self.delimiter)),
+            (_, _, Some(_)) if columns == 1 && time.is_none() => Err(ParseErrorKind::MissingDelimiter(self.delimiter)),
+            (None, _, _) => Err(ParseErrorKind::MissingColumn(self.station_column + 1)),
+            (_, None, _) => Err(ParseErrorKind::MissingColumn(self.value_column + 1)),
+            (_, _, Some(column)) if time.is_none() => Err(ParseErrorKind::MissingColumn(column + 1)),
+            (Some(station), Some(value), _) => Ok((station, value, time)),
+        }
+    }
+}
+
+/// One field of a line, with any surrounding quotes removed.
+pub struct Field<'a> {
+    pub text: &'a str,
+    /// The field contains doubled quotes that still need collapsing.
+    escaped: bool,
+}
+
+impl<'a> Field<'a> {
+    pub fn unescaped(&self, quote: Option<u8>) -> Cow<'a, str> {
+        match quote {
+            Some(quote) if self.escaped => {
+                let quote = quote as char;
+                let mut result = String::with_capacity(self.text.len());
+                let mut chars = self.text.chars();
+                while let Some(c) = chars.next() {
+                    result.push(c);
+                    if c == quote {
+                        chars.next();
+                    }
+                }
+                Cow::Owned(result)
+            }
+            _ => Cow::Borrowed(self.text),
+        }
+    }
+}
+
+/// Splits a line into fields. The delimiter and quote are ASCII, so we can search the bytes
+/// and still slice the `&str` on character boundaries.
+pub struct Fields<'a> {
+    text: &'a str,
+    index: usize,
+    delimiter: u8,
+    quote: Option<u8>,
+    done: bool,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(text: &'a str, delimiter: u8, quote: Option<u8>) -> Self {
+        Self {
+            text,
+            index: 0,
+            delimiter,
+            quote,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<Field<'a>, ParseErrorKind>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let start = self.index;
+        let bytes = self.text.as_bytes();
+        let rest = &bytes[start..];
+
+        let (field, end) = match (self.quote, rest.first()) {
+            (Some(quote), Some(&first)) if first == quote => {
+                // Find the closing quote, hopping over doubled ones.
+                let mut i = 1;
+                let mut escaped = false;
+                loop {
+                    match rest[i..].iter().position(|&b| b == quote) {
+                        None => {
+                            self.done = true;
+                            return Some(Err(ParseErrorKind::UnterminatedQuote));
+                        }
+                        Some(found) if rest.get(i + found + 1) == Some(&quote) => {
+                            escaped = true;
+                            i += found + 2;
+                        }
+                        Some(found) => {
+                            i += found;
+                            break;
+                        }
+                    }
+                }
+                let after = start + i + 1;
+                if after < bytes.len() && bytes[after] != self.delimiter {
+                    self.done = true;
+                    return Some(Err(ParseErrorKind::UnterminatedQuote));
+                }
+                (Field { text: &self.text[start + 1..start + i], escaped }, after)
+            }
+            _ => {
+                let end = rest
+                    .iter()
+                    .position(|&b| b == self.delimiter)
+                    .map_or(self.text.len(), |i| start + i);
+                (Field { text: &self.text[start..end], escaped: false }, end)
+            }
+        };
+
+        if end >= self.text.len() {
+            self.done = true;
+        } else {
+            self.index = end + 1;
+        }
+        Some(Ok(field))
+    }
+}
+
+/// A column picked on the command line: a 1-based position, or a name from the header row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(0) => Err("columns are numbered from 1".to_string()),
+            Ok(n) => Ok(Column::Index(n - 1)),
+            Err(_) if s.is_empty() => Err("empty column name".to_string()),
+            Err(_) => Ok(Column::Name(s.to_string())),
+        }
+    }
+}
+
+impl Column {
+    /// Finds the 0-based position of this column, looking names up in the header row.
+    pub fn resolve(&self, header: Option<&str>, delimiter: u8, quote: Option<u8>) -> Result<usize, String> {
+        match self {
+            Column::Index(index) => Ok(*index),
+            Column::Name(name) => {
+                let header = header.ok_or_else(|| format!("column {name:?} is named, but there's no --header"))?;
+                Fields::new(header, delimiter, quote)
+                    .position(|field| field.is_ok_and(|field| field.unescaped(quote) == name.as_str()))
+                    .ok_or_else(|| format!("no column named {name:?} in the header"))
+            }
+        }
+    }
+}
+
+/// Parses a delimiter given on the command line: a single ASCII character, or `tab`/`\t`.
+pub fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() && s != "\n" && s != "\r" => Ok(s.as_bytes()[0]),
+        _ => Err(format!("delimiter must be a single ASCII character, not {s:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv() -> Schema {
+        Schema {
+            delimiter: b',',
+            station_column: 2,
+            value_column: 0,
+            time_column: None,
+            header: true,
+            quote: Some(b'"'),
+        }
+    }
+
+    #[test]
+    fn picks_columns_and_ignores_the_rest() {
+        let (station, value, time) = csv().split("12.5,x,Oslo,extra").unwrap();
+        assert_eq!((station.as_ref(), value, time), ("Oslo", "12.5", None));
+        let timed = Schema { time_column: Some(3), ..csv() };
+        let (_, _, time) = timed.split("12.5,x,Oslo,2024-01-15T10:00Z").unwrap();
+        assert_eq!(time, Some("2024-01-15T10:00Z"));
+        assert_eq!(timed.split("12.5,x,Oslo").err(), Some(ParseErrorKind::MissingColumn(4)));
+    }
+
+    #[test]
+    fn quoted_fields_may_contain_delimiters_and_quotes() {
+        let (station, value, _) = csv().split(r#""-1.0",,"Washington, ""D.C.""""#).unwrap();
+        assert_eq!(station, r#"Washington, "D.C.""#);
+        assert_eq!(value, "-1.0");
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(csv().split("1.0,x").err(), Some(ParseErrorKind::MissingColumn(3)));
+        assert_eq!(csv().split("1.0;x;Oslo").err(), Some(ParseErrorKind::MissingDelimiter(b',')));
+        let tabs = Schema { delimiter: b'\t', ..csv() };
+        assert_eq!(tabs.split("1.0").unwrap_err().to_string(), "missing '\\t' delimiter");
+        assert_eq!(csv().split(r#"1.0,x,"Oslo"#).err(), Some(ParseErrorKind::UnterminatedQuote));
+        assert_eq!(csv().split(r#"1.0,x,"Os"lo"#).err(), Some(ParseErrorKind::UnterminatedQuote));
+    }
+
+    #[test]
+    fn columns_by_name() {
+        let header = r#"temp,id,"station name""#;
+        let column: Column = "station name".parse().unwrap();
+        assert_eq!(column.resolve(Some(header), b',', Some(b'"')), Ok(2));
+        assert!("nope".parse::<Column>().unwrap().resolve(Some(header), b',', None).is_err());
+        assert_eq!("1".parse::<Column>(), Ok(Column::Index(0)));
+    }
+}
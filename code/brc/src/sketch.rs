@@ -0,0 +1,186 @@
+//! Approximate percentiles in bounded memory, for readings too finely grained to count one by
+//! one.
+
+use std::collections::BTreeMap;
+use crate::ascii::Scale;
+use crate::histogram::{Percentile, Percentiles};
+
+/// A DDSketch: a histogram whose buckets grow geometrically, so that every reading lands in a
+/// bucket no wider than a fixed fraction of the reading itself.
+///
+/// Any percentile it reports is within `accuracy` of the true one, relatively speaking: at the
+/// default of 1% a true 20.0 comes back as something from 19.8 to 20.2. Everything from 0.001
+/// to 1,000,000 fits in under 1,100 buckets either side of zero at that accuracy, however many
+/// readings there are. Sketches with the same accuracy merge by adding their buckets together,
+/// just like [`Histogram`](crate::histogram::Histogram)s, so the result doesn't depend on how
+/// the input was split up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sketch {
+    accuracy: f64,
+    gamma: f64,
+    ln_gamma: f64,
+    /// Counts of positive readings, by bucket.
+    positive: BTreeMap<i32, u64>,
+    /// Counts of negative readings, by the bucket their magnitude falls in.
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    total: u64,
+}
+
+impl Sketch {
+    pub const DEFAULT_ACCURACY: f64 = 0.01;
+
+    /// A sketch whose percentiles are within `accuracy`, a fraction between 0 and 1, of the
+    /// truth.
+    pub fn new(accuracy: f64) -> Self {
+        assert!(accuracy > 0.0 && accuracy < 1.0, "a sketch's accuracy must be between 0 and 1");
+        let gamma = (1.0 + accuracy) / (1.0 - accuracy);
+        Sketch {
+            accuracy,
+            gamma,
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            total: 0,
+        }
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        self.accuracy
+    }
+
+    /// Adds a reading, which must be finite.
+    #[inline]
+    pub fn add(&mut self, value: f64) {
+        debug_assert!(value.is_finite());
+        if value > 0.0 {
+            *self.positive.entry(self.index(value)).or_default() += 1;
+        } else if value < 0.0 {
+            *self.negative.entry(self.index(-value)).or_default() += 1;
+        } else {
+            self.zero += 1;
+        }
+        self.total += 1;
+    }
+
+    /// Adds in everything `other` has seen. Both sketches must have the same accuracy.
+    pub fn merge(&mut self, other: &Sketch) {
+        assert_eq!(self.accuracy, other.accuracy, "only sketches with the same accuracy can be merged");
+        for (buckets, others) in [(&mut self.positive, &other.positive), (&mut self.negative, &other.negative)] {
+            for (&index, &count) in others {
+                *buckets.entry(index).or_default() += count;
+            }
+        }
+        self.zero += other.zero;
+        self.total += other.total;
+    }
+
+    /// How many readings there are.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Bucket `index` holds magnitudes greater than gamma^(index - 1) and no greater than
+    /// gamma^index.
+    #[inline(always)]
+    fn index(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// The magnitude that's within `accuracy` of everything in bucket `index`.
+    fn magnitude(&self, index: i32) -> f64 {
+        2.0 * (index as f64 * self.ln_gamma).exp() / (self.gamma + 1.0)
+    }
+
+    /// The estimated value at `percentile`, by the same nearest-rank method as
+    /// [`Histogram::percentile`](crate::histogram::Histogram::percentile), or `None` if there
+    /// aren't any readings.
+    pub fn percentile(&self, percentile: Percentile) -> Option<f64> {
+        let rank = percentile.rank(self.total)?;
+        // Most negative first, so the biggest magnitudes come first on that side
+        let negative = self.negative.iter().rev().map(|(&index, &count)| (-self.magnitude(index), count));
+        let zero = std::iter::once((0.0, self.zero));
+        let positive = self.positive.iter().map(|(&index, &count)| (self.magnitude(index), count));
+        let mut seen = 0;
+        negative.chain(zero).chain(positive).find_map(|(value, count)| {
+            seen += count;
+            (seen >= rank).then_some(value)
+        })
+    }
+
+    /// Each of `percentiles`, ready to print at `scale`, for a sketch of fixed-point readings.
+    pub fn percentiles(&self, percentiles: &[Percentile], scale: Scale) -> Percentiles {
+        Percentiles(
+            percentiles
+                .iter()
+                .filter_map(|&percentile| self.percentile(percentile))
+                .map(|value| scale.format(value.round() as i64))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spread over several orders of magnitude either side of zero, as no fixed-width histogram
+    /// could hold.
+    fn readings() -> Vec<f64> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..50_000)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let uniform = (state >> 11) as f64 / (1u64 << 53) as f64;
+                let magnitude = 10f64.powf(uniform * 9.0 - 3.0);
+                if i % 3 == 0 { -magnitude } else if i % 101 == 0 { 0.0 } else { magnitude }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn percentiles_are_within_the_accuracy_of_exact_ones() {
+        let readings = readings();
+        let mut sorted = readings.clone();
+        sorted.sort_by(f64::total_cmp);
+        for accuracy in [0.05, 0.01, 0.001] {
+            let mut parts: Vec<Sketch> = readings
+                .chunks(7000)
+                .map(|chunk| {
+                    let mut part = Sketch::new(accuracy);
+                    chunk.iter().for_each(|&value| part.add(value));
+                    part
+                })
+                .collect();
+            let mut sketch = parts.pop().unwrap();
+            parts.iter().for_each(|part| sketch.merge(part));
+            assert_eq!(sketch.count(), readings.len() as u64);
+            for percentile in ["0", "1", "5", "10", "25", "33.3", "50", "75", "90", "95", "99", "99.9", "100"] {
+                let percentile: Percentile = percentile.parse().unwrap();
+                let exact = sorted[percentile.rank(sorted.len() as u64).unwrap() as usize - 1];
+                let estimate = sketch.percentile(percentile).unwrap();
+                assert!(
+                    (estimate - exact).abs() <= exact.abs() * accuracy * (1.0 + 1e-9),
+                    "{percentile:?} at {accuracy}: {estimate} is too far from {exact}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn merging_matches_one_pass() {
+        let readings = readings();
+        let mut whole = Sketch::new(Sketch::DEFAULT_ACCURACY);
+        readings.iter().for_each(|&value| whole.add(value));
+        let mut merged = Sketch::new(Sketch::DEFAULT_ACCURACY);
+        for chunk in readings.chunks(999).rev() {
+            let mut part = Sketch::new(Sketch::DEFAULT_ACCURACY);
+            chunk.iter().for_each(|&value| part.add(value));
+            merged.merge(&part);
+        }
+        assert_eq!(merged, whole);
+    }
+}
@@ -0,0 +1,260 @@
+//! Exact percentiles in bounded memory, by sorting the readings on disk.
+//!
+//! Readings are kept in memory, tagged with their station, until there are as many as the
+//! memory limit allows. They're then sorted by station and value and written out as a run.
+//! At the end the runs are merged back together, which brings each station's readings past in
+//! order, and the ones at the ranks we're after are picked out on the way.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::histogram::Percentile;
+use crate::stats::Accumulator;
+
+/// A station number and a fixed-point value, as stored in a run.
+type Entry = (u32, i32);
+
+const ENTRY_SIZE: usize = 8;
+
+/// The most runs merged at once. Any more and they're merged in stages, so we don't run out of
+/// file handles.
+const MAX_FAN_IN: usize = 64;
+
+/// Readings gathered by station, for exact percentiles without keeping them all in memory.
+pub struct Spill {
+    directory: SpillDirectory,
+    /// Readings that haven't been written out yet.
+    buffer: Vec<Entry>,
+    capacity: usize,
+    runs: Vec<PathBuf>,
+    next_run: usize,
+    ids: HashMap<String, u32>,
+    stations: Vec<(String, Accumulator)>,
+}
+
+/// A station's totals from a [`Spill`], and its readings at each percentile asked for.
+pub struct SpilledStation {
+    pub name: String,
+    pub readings: Accumulator,
+    pub percentiles: Vec<i32>,
+}
+
+impl Spill {
+    /// A spill that writes its runs to a new directory inside `parent` whenever the readings it
+    /// holds would take up more than `memory` bytes.
+    pub fn new(parent: &Path, memory: usize) -> io::Result<Self> {
+        let capacity = (memory / ENTRY_SIZE).max(1);
+        Ok(Spill {
+            directory: SpillDirectory::create(parent)?,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            runs: Vec::new(),
+            next_run: 0,
+            ids: HashMap::new(),
+            stations: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, station: &str, value: i32) -> io::Result<()> {
+        let id = match self.ids.get(station) {
+            Some(&id) => id,
+            None => {
+                let id = self.stations.len() as u32;
+                self.ids.insert(station.to_string(), id);
+                self.stations.push((station.to_string(), Accumulator::default()));
+                id
+            }
+        };
+        self.stations[id as usize].1.add(value);
+        self.buffer.push((id, value));
+        if self.buffer.len() == self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// How many runs have been written out so far.
+    pub fn runs(&self) -> usize {
+        self.next_run
+    }
+
+    /// Sorts the readings in memory and writes them out as a run.
+    fn spill(&mut self) -> io::Result<()> {
+        self.buffer.sort_unstable();
+        let buffer = mem::take(&mut self.buffer);
+        let run = self.write_run(buffer.iter().copied().map(Ok))?;
+        self.runs.push(run);
+        self.buffer = buffer;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn write_run(&mut self, entries: impl Iterator<Item = io::Result<Entry>>) -> io::Result<PathBuf> {
+        let path = self.directory.path.join(format!("run-{}", self.next_run));
+        self.next_run += 1;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for entry in entries {
+            let (station, value) = entry?;
+            writer.write_all(&station.to_le_bytes())?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(path)
+    }
+
+    /// Merges everything back together, and picks out each station's readings at
+    /// `percentiles`, by the same nearest-rank method as
+    /// [`Histogram::percentile`](crate::histogram::Histogram::percentile).
+    pub fn finish(mut self, percentiles: &[Percentile]) -> io::Result<Vec<SpilledStation>> {
+        let sorted: Box<dyn Iterator<Item = io::Result<Entry>>> = if self.runs.is_empty() {
+            // It all fitted in memory, so there's nothing to merge
+            self.buffer.sort_unstable();
+            Box::new(mem::take(&mut self.buffer).into_iter().map(Ok))
+        } else {
+            if !self.buffer.is_empty() {
+                self.spill()?;
+            }
+            while self.runs.len() > MAX_FAN_IN {
+                let runs: Vec<PathBuf> = self.runs.drain(..MAX_FAN_IN).collect();
+                let merged = self.write_run(Merge::open(&runs)?)?;
+                runs.iter().try_for_each(fs::remove_file)?;
+                self.runs.push(merged);
+            }
+            Box::new(Merge::open(&self.runs)?)
+        };
+
+        let ranks: Vec<Vec<u64>> = self
+            .stations
+            .iter()
+            .map(|(_, readings)| percentiles.iter().filter_map(|percentile| percentile.rank(readings.count)).collect())
+            .collect();
+        let mut seen = vec![0; self.stations.len()];
+        let mut picked = vec![vec![0; percentiles.len()]; self.stations.len()];
+        for entry in sorted {
+            let (station, value) = entry?;
+            let station = station as usize;
+            seen[station] += 1;
+            for (&rank, pick) in ranks[station].iter().zip(picked[station].iter_mut()) {
+                if rank == seen[station] {
+                    *pick = value;
+                }
+            }
+        }
+
+        Ok(mem::take(&mut self.stations)
+            .into_iter()
+            .zip(picked)
+            .map(|((name, readings), percentiles)| SpilledStation { name, readings, percentiles })
+            .collect())
+    }
+}
+
+/// Several sorted runs read back as one.
+struct Merge {
+    runs: Vec<BufReader<File>>,
+    heads: BinaryHeap<Reverse<(Entry, usize)>>,
+}
+
+impl Merge {
+    fn open(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut merge = Merge {
+            runs: Vec::with_capacity(paths.len()),
+            heads: BinaryHeap::with_capacity(paths.len()),
+        };
+        for path in paths {
+            merge.runs.push(BufReader::new(File::open(path)?));
+            merge.advance(merge.runs.len() - 1)?;
+        }
+        Ok(merge)
+    }
+
+    /// Reads the next entry of run `index` into the heap, if there is one.
+    fn advance(&mut self, index: usize) -> io::Result<()> {
+        let mut bytes = [0; ENTRY_SIZE];
+        match self.runs[index].read_exact(&mut bytes) {
+            Ok(()) => {
+                let station = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+                let value = i32::from_le_bytes(bytes[4..].try_into().unwrap());
+                self.heads.push(Reverse(((station, value), index)));
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((entry, index)) = self.heads.pop()?;
+        Some(self.advance(index).map(|()| entry))
+    }
+}
+
+/// A directory of our own for the runs, removed along with them when we're done.
+struct SpillDirectory {
+    path: PathBuf,
+}
+
+impl SpillDirectory {
+    fn create(parent: &Path) -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let name = format!("brc-spill-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+            let path = parent.join(name);
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(SpillDirectory { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for SpillDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_match_sorting_in_memory() {
+        let readings: Vec<(String, i32)> = (0..20_000)
+            .map(|i: i32| (format!("Station {}", i % 7), i.wrapping_mul(1_000_003) % 100_000))
+            .collect();
+        let percentiles: Vec<Percentile> = ["0", "1", "50", "95", "99.9", "100"].iter().map(|p| p.parse().unwrap()).collect();
+        // Enough memory for everything, then so little that the runs have to be merged in stages
+        for memory in [1 << 20, 800] {
+            let mut spill = Spill::new(&std::env::temp_dir(), memory).unwrap();
+            for (station, value) in &readings {
+                spill.add(station, *value).unwrap();
+            }
+            let runs = spill.runs();
+            let directory = spill.directory.path.clone();
+            let stations = spill.finish(&percentiles).unwrap();
+            assert!(!directory.exists());
+            assert_eq!(runs > MAX_FAN_IN, memory == 800);
+            assert_eq!(stations.len(), 7);
+            for station in stations {
+                let mut values: Vec<i32> =
+                    readings.iter().filter(|(name, _)| *name == station.name).map(|(_, value)| *value).collect();
+                values.sort();
+                assert_eq!(station.readings.count, values.len() as u64);
+                let expected: Vec<i32> =
+                    percentiles.iter().map(|p| values[p.rank(values.len() as u64).unwrap() as usize - 1]).collect();
+                assert_eq!(station.percentiles, expected, "{} with {memory} bytes", station.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Adding up readings in fixed point, so the answer doesn't depend on the order they were
+//! added in.
+
+use std::fmt;
+use crate::ascii::Scale;
+use crate::histogram::{Histogram, Percentile, Percentiles};
+use crate::sketch::Sketch;
+
+/// The minimum, maximum, count, sum and sum of squares of one station's readings, as
+/// fixed-point values at some [`Scale`].
+///
+/// Integer addition is exact, so accumulators filled by any number of threads and merged in any
+/// order come out identical to a single pass over the file. Readings are `i32`s, so the `i64`
+/// sum can't overflow before 2^32 of them, and nor can the `i128` sum of squares.
+///
+/// An accumulator made by [`Accumulator::with_distribution`] also keeps track of how its
+/// readings are distributed, for percentiles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accumulator {
+    pub min: i32,
+    pub max: i32,
+    pub sum: i64,
+    pub sum_squares: i128,
+    pub count: u64,
+    pub distribution: Option<Box<Distribution>>,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator {
+            min: i32::MAX,
+            max: i32::MIN,
+            sum: 0,
+            sum_squares: 0,
+            count: 0,
+            distribution: None,
+        }
+    }
+}
+
+impl Accumulator {
+    /// An empty accumulator that keeps track of the distribution as well.
+    pub fn with_distribution(distribution: Distribution) -> Self {
+        Accumulator {
+            distribution: Some(Box::new(distribution)),
+            ..Self::default()
+        }
+    }
+
+    #[inline(always)]
+    pub fn add(&mut self, value: i32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as i64;
+        self.sum_squares += square(value);
+        self.count += 1;
+        if let Some(distribution) = self.distribution.as_mut() {
+            distribution.add(value);
+        }
+    }
+
+    /// Adds in everything `other` has seen.
+    pub fn merge(&mut self, other: &Accumulator) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+        self.count += other.count;
+        match (self.distribution.as_mut(), other.distribution.as_ref()) {
+            (Some(distribution), Some(other)) => distribution.merge(other),
+            (None, Some(other)) => self.distribution = Some(other.clone()),
+            _ => {}
+        }
+    }
+
+    /// The mean, rounded to the scale of the readings the way the challenge's reference
+    /// implementation does: to the nearest unit, with halves going up (towards positive
+    /// infinity), so 1.25 becomes 1.3 and -1.25 becomes -1.2.
+    pub fn mean(&self) -> i64 {
+        round_half_up(self.sum as i128, self.count as i128) as i64
+    }
+
+    /// The population variance of the readings, in the units they were read in, squared.
+    ///
+    /// The textbook one-pass formula loses everything to cancellation in floating point when the
+    /// spread is small next to the mean. Here n·Σx² - (Σx)² is worked out exactly in integers,
+    /// so the only rounding is in the final division.
+    pub fn variance(&self, scale: Scale) -> f64 {
+        let n = self.count as i128;
+        let spread = n * self.sum_squares - self.sum as i128 * self.sum as i128;
+        let factor = scale.factor() as f64;
+        spread as f64 / (n * n) as f64 / (factor * factor)
+    }
+
+    /// The population standard deviation, in the units the readings were read in.
+    pub fn std_dev(&self, scale: Scale) -> f64 {
+        self.variance(scale).sqrt()
+    }
+
+    /// Each of `wanted`, ready to print at `scale`, if any were wanted. The distribution may be
+    /// kept for something else, such as an export, so having one isn't enough.
+    pub fn percentiles(&self, wanted: &[Percentile], scale: Scale) -> Option<Percentiles> {
+        let distribution = self.distribution.as_ref().filter(|_| !wanted.is_empty())?;
+        Some(distribution.percentiles(wanted, scale))
+    }
+
+    /// Variance and standard deviation, ready to print at `scale`.
+    pub fn spread(&self, scale: Scale) -> Spread {
+        Spread {
+            variance: self.variance(scale),
+            std_dev: self.std_dev(scale),
+            digits: scale.digits() as usize,
+        }
+    }
+}
+
+/// What an accumulator keeps of its readings' distribution, for percentiles.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distribution {
+    /// Every reading counted, for exact percentiles.
+    Exact(Histogram),
+    /// Readings counted to within some relative accuracy, for when there are too many different
+    /// values to count them all.
+    Sketch(Sketch),
+}
+
+impl Distribution {
+    #[inline(always)]
+    pub fn add(&mut self, value: i32) {
+        match self {
+            Distribution::Exact(histogram) => histogram.add(value),
+            Distribution::Sketch(sketch) => sketch.add(value as f64),
+        }
+    }
+
+    /// Adds in everything `other` has seen, which must be the same kind of distribution.
+    pub fn merge(&mut self, other: &Distribution) {
+        match (self, other) {
+            (Distribution::Exact(histogram), Distribution::Exact(other)) => histogram.merge(other),
+            (Distribution::Sketch(sketch), Distribution::Sketch(other)) => sketch.merge(other),
+            _ => panic!("an exact distribution can't be merged with a sketch"),
+        }
+    }
+
+    /// Each of `percentiles`, ready to print at `scale`.
+    pub fn percentiles(&self, percentiles: &[Percentile], scale: Scale) -> Percentiles {
+        match self {
+            Distribution::Exact(histogram) => histogram.percentiles(percentiles, scale),
+            Distribution::Sketch(sketch) => sketch.percentiles(percentiles, scale),
+        }
+    }
+}
+
+#[inline(always)]
+fn square(value: i32) -> i128 {
+    (value as i64 * value as i64) as i128
+}
+
+/// `numerator / denominator` rounded to the nearest whole number, with halves going up.
+/// `denominator` must be positive.
+pub fn round_half_up(numerator: i128, denominator: i128) -> i128 {
+    (2 * numerator + denominator).div_euclid(2 * denominator)
+}
+
+/// How widely a station's readings are spread, which prints as `variance;std_dev` to as many
+/// decimal places as the readings have.
+#[derive(Debug, Clone, Copy)]
+pub struct Spread {
+    pub variance: f64,
+    pub std_dev: f64,
+    pub(crate) digits: usize,
+}
+
+impl fmt::Display for Spread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.*};{:.*}", self.digits, self.variance, self.digits, self.std_dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn means_round_halves_up() {
+        for (readings, mean) in [
+            (&[12, 13][..], 13),
+            (&[-12, -13], -12),
+            (&[1, 2, 2], 2),
+            (&[-1, -2, -2], -2),
+            (&[0, -1], 0),
+            (&[5], 5),
+        ] {
+            let mut accumulator = Accumulator::default();
+            readings.iter().for_each(|&value| accumulator.add(value));
+            assert_eq!(accumulator.mean(), mean, "{readings:?}");
+        }
+    }
+
+    #[test]
+    fn merging_in_any_order_matches_one_pass() {
+        let readings: Vec<i32> = (0..10_000).map(|i| (i * 7919 % 1999) - 999).collect();
+        let distributions = [
+            Distribution::Exact(Histogram::default()),
+            Distribution::Sketch(Sketch::new(Sketch::DEFAULT_ACCURACY)),
+        ];
+        let empties = distributions.into_iter().map(Accumulator::with_distribution);
+        for empty in std::iter::once(Accumulator::default()).chain(empties) {
+            let mut whole = empty.clone();
+            readings.iter().for_each(|&value| whole.add(value));
+            for pieces in [1, 3, 7, 64] {
+                let mut parts: Vec<Accumulator> = readings
+                    .chunks(readings.len().div_ceil(pieces))
+                    .map(|chunk| {
+                        let mut part = empty.clone();
+                        chunk.iter().for_each(|&value| part.add(value));
+                        part
+                    })
+                    .collect();
+                parts.reverse();
+                let mut merged = Accumulator::default();
+                parts.iter().for_each(|part| merged.merge(part));
+                assert_eq!(merged, whole, "{pieces} pieces");
+            }
+        }
+    }
+
+    /// Mean first, then the squared distances from it.
+    fn two_pass_variance(readings: &[i32], scale: Scale) -> f64 {
+        let factor = scale.factor() as f64;
+        let values: Vec<f64> = readings.iter().map(|&value| value as f64 / factor).collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn variance_matches_a_two_pass_reference() {
+        let spread_out: Vec<i32> = (0..100_000).map(|i| (i * 7919 % 1999) - 999).collect();
+        // A tiny spread around a large mean, which one pass in floats gets badly wrong
+        let bunched: Vec<i32> = (0..100_000).map(|i| 999_000_000 + i % 3).collect();
+        for (readings, scale) in [(&spread_out, Scale::TENTHS), (&bunched, Scale::new(3).unwrap())] {
+            let mut parts: Vec<Accumulator> = readings
+                .chunks(997)
+                .map(|chunk| {
+                    let mut part = Accumulator::default();
+                    chunk.iter().for_each(|&value| part.add(value));
+                    part
+                })
+                .collect();
+            parts.reverse();
+            let mut merged = Accumulator::default();
+            parts.iter().for_each(|part| merged.merge(part));
+            let expected = two_pass_variance(readings, scale);
+            let variance = merged.variance(scale);
+            assert!((variance - expected).abs() <= expected * 1e-6, "{variance} != {expected}");
+            assert!((merged.std_dev(scale) - expected.sqrt()).abs() <= expected.sqrt() * 1e-6);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! How many workers to run, and where.
+
+use std::fs;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::thread::available_parallelism;
+
+/// A list of CPU cores given on the command line, such as `0,2,4` or `0-3,8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreList(pub Vec<usize>);
+
+impl FromStr for CoreList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err("no cores given".to_string());
+        }
+        let mut cores = Vec::new();
+        for part in s.split(',') {
+            let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("invalid core {n:?} in {s:?}"));
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse(first)?, parse(last)?);
+                    if first > last {
+                        return Err(format!("core range {part:?} is backwards"));
+                    }
+                    cores.extend(first..=last);
+                }
+                None => cores.push(parse(part)?),
+            }
+        }
+        // Pinning goes round the list in turn, so a core listed twice would get twice the work
+        let mut seen = cores.clone();
+        seen.sort_unstable();
+        if let Some(core) = seen.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("core {} is listed more than once in {s:?}", core[0]));
+        }
+        Ok(CoreList(cores))
+    }
+}
+
+/// The CPU quota of the cgroup we're running in, in CPUs, if there is one. Checks the unified
+/// (v2) hierarchy first, then the v1 `cpu` controller, whatever order `/proc/self/cgroup` lists
+/// them in.
+pub fn cgroup_cpu_quota() -> Option<f64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    cgroup_quota(&cgroups, |path| fs::read_to_string(format!("/sys/fs/cgroup{path}")).ok())
+}
+
+/// Finds the quota given `/proc/self/cgroup` and a way to read files under `/sys/fs/cgroup`.
+fn cgroup_quota(cgroups: &str, read: impl Fn(&str) -> Option<String>) -> Option<f64> {
+    let hierarchies = cgroups.lines().filter_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        Some((controllers, path))
+    });
+    let unified = hierarchies.clone().filter(|(controllers, _)| controllers.is_empty()).find_map(|(_, path)| {
+        parse_cpu_max(&read(&format!("{path}/cpu.max"))?)
+    });
+    unified.or_else(|| {
+        let mut v1 = hierarchies.filter(|(controllers, _)| controllers.split(',').any(|controller| controller == "cpu"));
+        v1.find_map(|(_, path)| {
+            ["cpu", "cpu,cpuacct", "cpuacct,cpu"].into_iter().find_map(|mount| {
+                let read = |file: &str| read(&format!("/{mount}{path}/{file}"));
+                // The first mount that has the files settles it, limit or not
+                Some(parse_cfs_quota(&read("cpu.cfs_quota_us")?, &read("cpu.cfs_period_us")?))
+            })?
+        })
+    })
+}
+
+/// Parses a cgroup v2 `cpu.max`: `max 100000` means no limit, and `<quota> <period>` allows
+/// `quota / period` CPUs.
+fn parse_cpu_max(max: &str) -> Option<f64> {
+    let mut fields = max.split_whitespace();
+    let (quota, period) = (fields.next()?.parse::<f64>().ok()?, fields.next()?.parse::<f64>().ok()?);
+    (quota > 0.0 && period > 0.0).then_some(quota / period)
+}
+
+/// Parses a cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us`, where a quota of -1 means no
+/// limit.
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<f64> {
+    let (quota, period) = (quota.trim().parse::<f64>().ok()?, period.trim().parse::<f64>().ok()?);
+    (quota > 0.0 && period > 0.0).then_some(quota / period)
+}
+
+/// How many CPUs we can actually use: the ones we're allowed to run on, capped by any cgroup
+/// quota (rounded up, since a quota of 1.5 CPUs still runs two threads faster than one).
+/// Always at least one.
+///
+/// std's `available_parallelism` reads cgroup quotas too, but rounds them down, so it would
+/// give one thread for 1.5 CPUs. Hence the affinity mask is counted directly, and std is only
+/// asked when that can't be had.
+pub fn available_cpus() -> usize {
+    let cpus = match core_affinity::get_core_ids() {
+        Some(cores) if !cores.is_empty() => cores.len(),
+        _ => available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    match cgroup_cpu_quota() {
+        Some(quota) => usize::min(cpus, quota.ceil() as usize).max(1),
+        None => cpus,
+    }
+}
+
+/// Pins the calling thread to `core`. Returns false if the platform or the core said no.
+pub fn pin_to_core(core: usize) -> bool {
+    core_affinity::set_for_current(core_affinity::CoreId { id: core })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_lists() {
+        assert_eq!("0-3,8".parse(), Ok(CoreList(vec![0, 1, 2, 3, 8])));
+        assert_eq!(" 5 , 1-2".parse(), Ok(CoreList(vec![5, 1, 2])));
+        assert_eq!("4-4".parse(), Ok(CoreList(vec![4])));
+        assert_eq!("3-1".parse::<CoreList>(), Err("core range \"3-1\" is backwards".to_string()));
+        assert_eq!("0-3,2".parse::<CoreList>(), Err("core 2 is listed more than once in \"0-3,2\"".to_string()));
+        assert_eq!("1,1".parse::<CoreList>(), Err("core 1 is listed more than once in \"1,1\"".to_string()));
+        assert_eq!("".parse::<CoreList>(), Err("no cores given".to_string()));
+        assert_eq!("0,".parse::<CoreList>(), Err("invalid core \"\" in \"0,\"".to_string()));
+        assert_eq!("a-2".parse::<CoreList>(), Err("invalid core \"a\" in \"a-2\"".to_string()));
+    }
+
+    #[test]
+    fn cgroup_quotas() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("50000 100000\n"), Some(0.5));
+        assert_eq!(parse_cpu_max("250000 100000"), Some(2.5));
+        assert_eq!(parse_cpu_max(""), None);
+        assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs_quota("150000\n", "100000\n"), Some(1.5));
+        assert_eq!(parse_cfs_quota("150000", "0"), None);
+    }
+
+    #[test]
+    fn unified_hierarchy_comes_first() {
+        let files = [
+            ("/app/cpu.max", "200000 100000"),
+            ("/cpu,cpuacct/app/cpu.cfs_quota_us", "50000"),
+            ("/cpu,cpuacct/app/cpu.cfs_period_us", "100000"),
+        ];
+        let read = |path: &str| files.iter().find(|(file, _)| *file == path).map(|(_, contents)| contents.to_string());
+        // The v1 line comes first, but the v2 quota still wins
+        assert_eq!(cgroup_quota("4:cpu,cpuacct:/app\n0::/app\n", read), Some(2.0));
+        assert_eq!(cgroup_quota("4:cpu,cpuacct:/app\n0::/other\n", read), Some(0.5));
+        assert_eq!(cgroup_quota("4:memory:/app\n", read), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! Readings grouped into windows of time, for a time series per station.
+//!
+//! Timestamps are turned into seconds since the Unix epoch, and everything is in UTC. Windows
+//! line up with the start of 1970: hours start on the hour, days at midnight and months on the
+//! 1st. Each station keeps an accumulator per window it has readings in, so a window that
+//! straddles two chunks (or two threads, or two inputs) is put back together by merging the
+//! accumulators with the same station and start, just like the totals for a station are.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use crate::error::ParseErrorKind;
+use crate::stats::Accumulator;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// The most windows a sliding window's step lets a single reading fall into.
+const MAX_OVERLAP: i64 = 1_000;
+
+/// The longest a window can be, 10,000 years, in days and in months. Timestamps are at most
+/// 18 digits long, so with this the window arithmetic can't overflow.
+const MAX_SPAN_DAYS: i64 = 3_652_425;
+const MAX_SPAN_MONTHS: i64 = 120_000;
+
+/// Parses a timestamp into seconds since the Unix epoch: either that number itself, such as
+/// `1705312800`, or an ISO 8601 date with an optional time and UTC offset, such as
+/// `2024-01-15`, `2024-01-15T10:00Z` or `2024-01-15 11:00:00.250+01:00`. Fractions of a
+/// second are dropped, and a time without an offset is taken to be UTC.
+pub fn parse_timestamp(text: &str) -> Result<i64, ParseErrorKind> {
+    let bytes = text.as_bytes();
+    if bytes.len() > 4 && bytes[4] == b'-' {
+        parse_iso(bytes).ok_or(ParseErrorKind::InvalidTimestamp)
+    } else {
+        parse_epoch(bytes).ok_or(ParseErrorKind::InvalidTimestamp)
+    }
+}
+
+/// Seconds since the epoch, perhaps with a fraction, which rounds down.
+fn parse_epoch(bytes: &[u8]) -> Option<i64> {
+    let (negative, bytes) = match bytes.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, bytes),
+    };
+    let (whole, fraction) = match bytes.iter().position(|&b| b == b'.') {
+        Some(point) => (&bytes[..point], &bytes[point + 1..]),
+        None => (bytes, &[][..]),
+    };
+    if whole.is_empty() || !fraction.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let seconds = number(whole)?;
+    let has_fraction = fraction.iter().any(|&b| b != b'0');
+    Some(match (negative, has_fraction) {
+        (false, _) => seconds,
+        (true, false) => -seconds,
+        (true, true) => -seconds - 1,
+    })
+}
+
+/// `YYYY-MM-DD`, then optionally `THH:MM`, `:SS`, `.fff` and `Z` or `±HH:MM`.
+fn parse_iso(bytes: &[u8]) -> Option<i64> {
+    let year = number(bytes.get(0..4)?)?;
+    let month = two_digits(bytes, 5)?;
+    let day = two_digits(bytes, 8)?;
+    if bytes.get(7) != Some(&b'-') || !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let days = days_from_civil(year, month as u32, day as u32);
+    // Rejects the 30th of February and the like
+    if civil_from_days(days) != (year, month as u32, day as u32) {
+        return None;
+    }
+    let mut seconds = days * SECONDS_PER_DAY;
+    let mut rest = &bytes[10..];
+    if let Some((b'T' | b't' | b' ', time)) = rest.split_first() {
+        let (hour, minute) = (two_digits(time, 0)?, two_digits(time, 3)?);
+        if time.get(2) != Some(&b':') || hour > 23 || minute > 59 {
+            return None;
+        }
+        seconds += hour * 3600 + minute * 60;
+        rest = &time[5..];
+        if let Some((b':', time)) = rest.split_first() {
+            let second = two_digits(time, 0)?;
+            // Allowing for a leap second
+            if second > 60 {
+                return None;
+            }
+            seconds += second;
+            rest = &time[2..];
+            if let Some((b'.', time)) = rest.split_first() {
+                let digits = time.iter().take_while(|b| b.is_ascii_digit()).count();
+                if digits == 0 {
+                    return None;
+                }
+                rest = &time[digits..];
+            }
+        }
+    }
+    match rest {
+        [] | [b'Z' | b'z'] => Some(seconds),
+        [sign @ (b'+' | b'-'), offset @ ..] => {
+            let hours = two_digits(offset, 0)?;
+            let minutes = match offset.len() {
+                2 => 0,
+                4 => two_digits(offset, 2)?,
+                5 if offset[2] == b':' => two_digits(offset, 3)?,
+                _ => return None,
+            };
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            // 10:00+01:00 is 09:00 UTC
+            Some(if *sign == b'+' { seconds - offset } else { seconds + offset })
+        }
+        _ => None,
+    }
+}
+
+/// A run of ASCII digits, as long as it fits.
+fn number(digits: &[u8]) -> Option<i64> {
+    if digits.is_empty() || digits.len() > 18 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(digits.iter().fold(0, |number, &digit| number * 10 + (digit - b'0') as i64))
+}
+
+fn two_digits(bytes: &[u8], at: usize) -> Option<i64> {
+    number(bytes.get(at..at + 2)?)
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar. Howard Hinnant's algorithm,
+/// which counts years from March so that the leap day comes last.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The year, month and day `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u32;
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// A length of time: a fixed number of seconds, or a number of calendar months, which vary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Span {
+    Seconds(i64),
+    Months(i64),
+}
+
+impl FromStr for Span {
+    type Err = String;
+
+    /// A count (1 if it's left out) and a unit: `hour`, `day`, `month`, `15m`, `6h`, `2w` or
+    /// `3months`, say.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let too_long = || "a length of time can't be more than 10,000 years".to_string();
+        let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+        let count = match &s[..digits] {
+            "" => 1,
+            // They're all digits, so it can only be too big
+            count => count.parse::<i64>().map_err(|_| too_long())?,
+        };
+        let (unit, months) = match s[digits..].trim_start() {
+            "s" | "sec" | "second" | "seconds" => (1, false),
+            "m" | "min" | "minute" | "minutes" => (60, false),
+            "h" | "hour" | "hours" => (3600, false),
+            "d" | "day" | "days" => (SECONDS_PER_DAY, false),
+            "w" | "week" | "weeks" => (7 * SECONDS_PER_DAY, false),
+            "mo" | "month" | "months" => (1, true),
+            "y" | "year" | "years" => (12, true),
+            unit => return Err(format!("unknown unit of time {unit:?}: use s, m, h, d, w, month or year")),
+        };
+        let count = count.checked_mul(unit).ok_or_else(too_long)?;
+        let max = if months { MAX_SPAN_MONTHS } else { MAX_SPAN_DAYS * SECONDS_PER_DAY };
+        match (count, months) {
+            (0, _) => Err("a length of time can't be zero".to_string()),
+            (count, _) if count > max => Err(too_long()),
+            (count, false) => Ok(Span::Seconds(count)),
+            (count, true) => Ok(Span::Months(count)),
+        }
+    }
+}
+
+/// Which windows each reading belongs to: back-to-back windows (tumbling), or windows that
+/// start every `step` and overlap (sliding).
+///
+/// Both are worked out in whole units, seconds or months, since the epoch: a window of `size`
+/// units starts at every multiple of `step` units, and a reading is in every window that has
+/// started and not yet ended when it was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindows {
+    size: i64,
+    step: i64,
+    months: bool,
+}
+
+impl TimeWindows {
+    pub fn tumbling(size: Span) -> Self {
+        match size {
+            Span::Seconds(seconds) => TimeWindows { size: seconds, step: seconds, months: false },
+            Span::Months(months) => TimeWindows { size: months, step: months, months: true },
+        }
+    }
+
+    /// Windows of `size` that start every `step`, which must be no longer than them and
+    /// measured in the same way: both in months, or both in hours, days and so on.
+    pub fn sliding(size: Span, step: Span) -> Result<Self, String> {
+        let windows = match (size, step) {
+            (Span::Seconds(size), Span::Seconds(step)) => TimeWindows { size, step, months: false },
+            (Span::Months(size), Span::Months(step)) => TimeWindows { size, step, months: true },
+            _ => return Err("a window counted in months can only slide by months, and vice versa".to_string()),
+        };
+        if windows.step > windows.size {
+            return Err("a window can't slide by more than its own length".to_string());
+        }
+        if (windows.size + windows.step - 1) / windows.step > MAX_OVERLAP {
+            return Err(format!("a window can't slide by less than 1/{MAX_OVERLAP} of its length"));
+        }
+        Ok(windows)
+    }
+
+    /// The start of every window a reading taken at `time` belongs to, earliest first.
+    #[inline]
+    pub fn starts(&self, time: i64) -> impl Iterator<Item = i64> + '_ {
+        let unit = if self.months {
+            let (year, month, _) = civil_from_days(time.div_euclid(SECONDS_PER_DAY));
+            (year - 1970) * 12 + month as i64 - 1
+        } else {
+            time
+        };
+        let first = (unit - self.size).div_euclid(self.step) + 1;
+        let last = unit.div_euclid(self.step);
+        (first..=last).map(move |window| {
+            let start = window * self.step;
+            if self.months {
+                let year = 1970 + start.div_euclid(12);
+                days_from_civil(year, start.rem_euclid(12) as u32 + 1, 1) * SECONDS_PER_DAY
+            } else {
+                start
+            }
+        })
+    }
+}
+
+/// Seconds since the epoch, which print as an ISO 8601 date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub i64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0.div_euclid(SECONDS_PER_DAY));
+        let seconds = self.0.rem_euclid(SECONDS_PER_DAY);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+        )
+    }
+}
+
+/// Each station's readings in each window it has any in. Windows without readings are left
+/// out.
+#[derive(Debug, Clone, Default)]
+pub struct TimeSeries {
+    stations: HashMap<String, BTreeMap<i64, Accumulator>>,
+}
+
+impl TimeSeries {
+    /// Adds a reading taken at `time` to each of its `windows`, starting any new ones from
+    /// `empty`.
+    #[inline]
+    pub fn add(&mut self, station: &str, time: i64, value: i32, windows: &TimeWindows, empty: &Accumulator) {
+        if !self.stations.contains_key(station) {
+            self.stations.insert(station.to_string(), BTreeMap::new());
+        }
+        let series = self.stations.get_mut(station).unwrap();
+        for start in windows.starts(time) {
+            series.entry(start).or_insert_with(|| empty.clone()).add(value);
+        }
+    }
+
+    /// Adds in everything `other` has seen.
+    pub fn merge(&mut self, other: TimeSeries) {
+        for (station, windows) in other.stations {
+            let series = self.stations.entry(station).or_default();
+            for (start, readings) in windows {
+                match series.get_mut(&start) {
+                    Some(series) => series.merge(&readings),
+                    None => {
+                        series.insert(start, readings);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every window of every station, by station name and then by when it starts.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Timestamp, &Accumulator)> {
+        let mut stations: Vec<_> = self.stations.iter().collect();
+        stations.sort_unstable_by_key(|(station, _)| *station);
+        stations.into_iter().flat_map(|(station, windows)| {
+            windows.iter().map(move |(&start, readings)| (station.as_str(), Timestamp(start), readings))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("1705312800"), Ok(1_705_312_800));
+        assert_eq!(parse_timestamp("-1.5"), Ok(-2));
+        assert_eq!(parse_timestamp("2024-01-15"), Ok(1_705_276_800));
+        assert_eq!(parse_timestamp("2024-01-15T10:00Z"), Ok(1_705_312_800));
+        assert_eq!(parse_timestamp("2024-01-15 11:00:00.250+01:00"), Ok(1_705_312_800));
+        assert_eq!(parse_timestamp("2024-02-29T00:00:00"), Ok(1_709_164_800));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Ok(-1));
+        for bad in ["", "2023-02-29", "2024-13-01", "2024-01-15T24:00", "2024-01-15T10:00+1", "yesterday", "2024-1-5"] {
+            assert_eq!(parse_timestamp(bad), Err(ParseErrorKind::InvalidTimestamp), "{bad:?}");
+        }
+        assert_eq!(Timestamp(1_709_164_805).to_string(), "2024-02-29T00:00:05Z");
+        assert_eq!(Timestamp(-1).to_string(), "1969-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn windows() {
+        let at = |text| parse_timestamp(text).unwrap();
+        let starts = |windows: TimeWindows, text| windows.starts(at(text)).map(|start| Timestamp(start).to_string()).collect::<Vec<_>>();
+        let hour = TimeWindows::tumbling("hour".parse().unwrap());
+        assert_eq!(starts(hour, "2024-01-15T10:59:59Z"), ["2024-01-15T10:00:00Z"]);
+        let quarter = TimeWindows::tumbling("3months".parse().unwrap());
+        assert_eq!(starts(quarter, "2024-06-30T23:00Z"), ["2024-04-01T00:00:00Z"]);
+        assert_eq!(starts(quarter, "1969-11-05"), ["1969-10-01T00:00:00Z"]);
+        let sliding = TimeWindows::sliding("1d".parse().unwrap(), "6h".parse().unwrap()).unwrap();
+        assert_eq!(
+            starts(sliding, "2024-01-15T13:00Z"),
+            ["2024-01-14T18:00:00Z", "2024-01-15T00:00:00Z", "2024-01-15T06:00:00Z", "2024-01-15T12:00:00Z"],
+        );
+        assert!(TimeWindows::sliding("month".parse().unwrap(), "1d".parse().unwrap()).is_err());
+        assert!(TimeWindows::sliding("1h".parse().unwrap(), "1d".parse().unwrap()).is_err());
+        assert!("0h".parse::<Span>().is_err());
+    }
+
+    #[test]
+    fn lengths_of_time_are_capped() {
+        assert_eq!("10000y".parse::<Span>(), Ok(Span::Months(MAX_SPAN_MONTHS)));
+        assert_eq!("3652425d".parse::<Span>(), Ok(Span::Seconds(MAX_SPAN_DAYS * SECONDS_PER_DAY)));
+        for long in ["10001y", "3652426d", "9000000000000000000s", "99999999999999999999s"] {
+            assert!(long.parse::<Span>().is_err(), "{long:?}");
+        }
+        // The longest windows, on the earliest and latest timestamps, don't overflow
+        let longest = MAX_SPAN_DAYS * SECONDS_PER_DAY;
+        let sliding = TimeWindows::sliding(Span::Seconds(longest), Span::Seconds(longest)).unwrap();
+        let months = TimeWindows::tumbling(Span::Months(MAX_SPAN_MONTHS));
+        for time in ["-999999999999999999", "999999999999999999"] {
+            let time = parse_timestamp(time).unwrap();
+            assert_eq!(sliding.starts(time).count(), 1);
+            assert_eq!(months.starts(time).count(), 1);
+        }
+    }
+
+    #[test]
+    fn merging_chunks_matches_one_pass() {
+        let windows = TimeWindows::sliding("day".parse().unwrap(), "8h".parse().unwrap()).unwrap();
+        let readings: Vec<(i64, i32)> = (0..5000).map(|i| (1_700_000_000 + i * 997, (i * 7919 % 400) as i32 - 200)).collect();
+        let mut whole = TimeSeries::default();
+        readings.iter().for_each(|&(time, value)| whole.add("A", time, value, &windows, &Accumulator::default()));
+        let mut merged = TimeSeries::default();
+        for chunk in readings.chunks(333) {
+            let mut part = TimeSeries::default();
+            chunk.iter().for_each(|&(time, value)| part.add("A", time, value, &windows, &Accumulator::default()));
+            merged.merge(part);
+        }
+        assert!(whole.iter().eq(merged.iter()));
+        assert_eq!(whole.iter().map(|(_, _, readings)| readings.count).sum::<u64>(), 3 * readings.len() as u64);
+    }
+}
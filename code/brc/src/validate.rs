@@ -0,0 +1,178 @@
+//! Keeping NaN, infinities and absurd readings out of the accumulators.
+
+use std::collections::HashMap;
+use std::fmt;
+use clap::ValueEnum;
+use crate::ascii::Scale;
+use crate::error::ParseErrorKind;
+
+/// What to do with a value that parsed fine but isn't a plausible reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum InvalidValueAction {
+    /// Treat the line as bad input, subject to the error policy.
+    #[default]
+    Reject,
+    /// Leave the value out of the results, but count it against its station.
+    Quarantine,
+}
+
+/// Decides whether a parsed value should be accumulated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValueFilter {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub action: InvalidValueAction,
+}
+
+impl ValueFilter {
+    /// Checks a fixed-point `value` at `scale`: `Ok(Some(value))` to keep it, `Ok(None)` to
+    /// quarantine it, or the reason it was rejected.
+    #[inline(always)]
+    pub fn check(&self, value: i32, scale: Scale) -> Result<Option<i32>, ParseErrorKind> {
+        if self.min.is_none() && self.max.is_none() {
+            return Ok(Some(value));
+        }
+        // Compare as the float the text would have parsed to, so a limit of 0.7 keeps 0.7
+        let reading = (value as f64 / scale.factor() as f64) as f32;
+        if self.out_of_range(reading) {
+            self.act_on(ParseErrorKind::OutOfRange)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    /// Checks a finite reading that has no fixed-point form, such as `1e9`, which failed to
+    /// parse with `problem`. If it's outside the limits it's dealt with like any other value
+    /// that is; otherwise it's still bad input.
+    pub fn check_float(&self, reading: f32, problem: ParseErrorKind) -> Result<Option<i32>, ParseErrorKind> {
+        if self.out_of_range(reading) {
+            self.act_on(ParseErrorKind::OutOfRange)
+        } else {
+            Err(problem)
+        }
+    }
+
+    fn out_of_range(&self, reading: f32) -> bool {
+        self.min.is_some_and(|min| reading < min) || self.max.is_some_and(|max| reading > max)
+    }
+
+    /// What to do with a NaN or an infinity, which have no fixed-point form.
+    pub fn non_finite(&self) -> Result<Option<i32>, ParseErrorKind> {
+        self.act_on(ParseErrorKind::NonFinite)
+    }
+
+    fn act_on(&self, problem: ParseErrorKind) -> Result<Option<i32>, ParseErrorKind> {
+        match self.action {
+            InvalidValueAction::Quarantine => Ok(None),
+            InvalidValueAction::Reject => Err(problem),
+        }
+    }
+}
+
+/// Per-station counts of quarantined values.
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    stations: HashMap<String, u64>,
+}
+
+impl Quarantine {
+    pub fn exclude(&mut self, station: &str) {
+        if let Some(count) = self.stations.get_mut(station) {
+            *count += 1;
+        } else {
+            self.stations.insert(station.to_string(), 1);
+        }
+    }
+
+    pub fn merge(&mut self, other: Quarantine) {
+        for (station, count) in other.stations {
+            *self.stations.entry(station).or_default() += count;
+        }
+    }
+
+    /// Total number of values left out.
+    pub fn total(&self) -> u64 {
+        self.stations.values().sum()
+    }
+}
+
+impl fmt::Display for Quarantine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Excluded {} invalid value(s)", self.total())?;
+        let mut stations: Vec<_> = self.stations.iter().collect();
+        stations.sort();
+        for (station, count) in stations {
+            write!(f, "\n  {station}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::Records;
+
+    fn filter(min: Option<f32>, max: Option<f32>, action: InvalidValueAction) -> ValueFilter {
+        ValueFilter { min, max, action }
+    }
+
+    #[test]
+    fn limits_are_inclusive() {
+        let reject = filter(Some(-10.0), Some(0.7), InvalidValueAction::Reject);
+        assert_eq!(reject.check(-100, Scale::TENTHS), Ok(Some(-100)));
+        assert_eq!(reject.check(7, Scale::TENTHS), Ok(Some(7)));
+        assert_eq!(reject.check(8, Scale::TENTHS), Err(ParseErrorKind::OutOfRange));
+        assert_eq!(reject.check(-101, Scale::TENTHS), Err(ParseErrorKind::OutOfRange));
+        // Only one limit, at another scale
+        let hundredths = Scale::new(2).unwrap();
+        let below = filter(None, Some(1.5), InvalidValueAction::Reject);
+        assert_eq!(below.check(i32::MIN, hundredths), Ok(Some(i32::MIN)));
+        assert_eq!(below.check(151, hundredths), Err(ParseErrorKind::OutOfRange));
+        // No limits at all
+        assert_eq!(ValueFilter::default().check(i32::MAX, Scale::TENTHS), Ok(Some(i32::MAX)));
+    }
+
+    #[test]
+    fn actions() {
+        for (action, out_of_range, non_finite) in [
+            (InvalidValueAction::Reject, Err(ParseErrorKind::OutOfRange), Err(ParseErrorKind::NonFinite)),
+            (InvalidValueAction::Quarantine, Ok(None), Ok(None)),
+        ] {
+            let filter = filter(Some(-99.9), Some(99.9), action);
+            assert_eq!(filter.check(1000, Scale::TENTHS), out_of_range);
+            assert_eq!(filter.check_float(1e9, ParseErrorKind::InvalidByte(b'e')), out_of_range);
+            assert_eq!(filter.check_float(-1e9, ParseErrorKind::InvalidByte(b'e')), out_of_range);
+            assert_eq!(filter.check_float(1.25, ParseErrorKind::TooManyFractionDigits), Err(ParseErrorKind::TooManyFractionDigits));
+            assert_eq!(filter.non_finite(), non_finite);
+        }
+    }
+
+    #[test]
+    fn non_finite_readings() {
+        // NaN and the infinities are never plausible, even with no limits set
+        for text in ["A;NaN", "B;inf", "C;-inf", "D;-Infinity"] {
+            let record = Records::new(text.as_bytes(), 0).next().unwrap().unwrap();
+            let reject = record.value_checked(Scale::TENTHS, &ValueFilter::default());
+            assert_eq!(reject.map_err(|e| e.kind), Err(ParseErrorKind::NonFinite), "{text}");
+            let quarantine = filter(None, None, InvalidValueAction::Quarantine);
+            assert_eq!(record.value_checked(Scale::TENTHS, &quarantine), Ok(None), "{text}");
+        }
+    }
+
+    #[test]
+    fn quarantine_counts_per_station() {
+        let mut first = Quarantine::default();
+        first.exclude("Oslo");
+        first.exclude("Oslo");
+        first.exclude("Lima");
+        let mut second = Quarantine::default();
+        second.exclude("Oslo");
+        second.exclude("Abha");
+        first.merge(second);
+        assert_eq!(first.total(), 5);
+        assert_eq!(first.stations.get("Oslo"), Some(&3));
+        assert_eq!(first.to_string(), "Excluded 5 invalid value(s)\n  Abha: 1\n  Lima: 1\n  Oslo: 3");
+        assert_eq!(Quarantine::default().to_string(), "Excluded 0 invalid value(s)");
+    }
+}
@@ -56,7 +56,7 @@ fn main() -> anyhow::Result<()> {
         let station = stations.choose(&mut rng)
             .context("No weather station found")?;
         let line = format!("{};{:.1}\n", station.id, station.measurement(&mut rng));
-        stream.write(line.as_bytes())?;
+        stream.write_all(line.as_bytes())?;
     }
     stream.flush()?;
     println!("Finished in {:.2} seconds", start.elapsed().as_secs_f32());
@@ -1,80 +1,233 @@
-use std::fs::File;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
+use brc::ascii::{Fixed, Scale};
+use brc::blocks::{Backend, BlockOptions, Blocks, DEFAULT_BLOCK_SIZE};
+use brc::cli::{parse_size, AdviceArgs, ErrorArgs, InputArgs, RankArgs, ScaleArgs, StatsArgs, SourcesArgs, ValueArgs, SchemaArgs, ThreadArgs, WindowArgs};
+use brc::error::ErrorReport;
+use brc::input::{is_stream, Piece, Windows};
+use brc::mapping::PageFaults;
+use brc::scan::Records;
+use brc::histogram::Percentiles;
+use brc::stats::{Accumulator, Spread};
+use brc::validate::Quarantine;
+use clap::Parser;
 use rustc_hash::FxHashMap;
 
-#[derive(Default)]
-struct StationReadings {
-    min: f32,
-    max: f32,
-    sum: f32,
-    count: usize,
+type Stations = FxHashMap<String, Accumulator>;
+
+/// Adds the readings in `other` to `result`.
+fn merge(result: &mut Stations, other: Stations) {
+    for (station, readings) in other {
+        if let Some(result) = result.get_mut(&station) {
+            result.merge(&readings);
+        } else {
+            result.insert(station, readings);
+        }
+    }
+}
+
+/// What was found in one input.
+struct InputResults {
+    path: PathBuf,
+    stations: Stations,
+    errors: ErrorReport,
+}
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    sources: SourcesArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    window: WindowArgs,
+
+    /// How much of a pipe or compressed input, which can't be mapped, to read at a time, such
+    /// as 4M. Defaults to 16M
+    #[arg(long, value_parser = parse_size)]
+    block_size: Option<usize>,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    schema: SchemaArgs,
+
+    #[command(flatten)]
+    scale: ScaleArgs,
+
+    #[command(flatten)]
+    values: ValueArgs,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    #[command(flatten)]
+    rank: RankArgs,
+
+    #[command(flatten)]
+    advice: AdviceArgs,
+
+    // Reading is single threaded, but decompressing a compressed input needn't be
+    #[command(flatten)]
+    threads: ThreadArgs,
 }
 
-fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
-    let mut i = start;
-    while memory_map[i] != character as u8 {
-        i += 1;
+fn read_files(args: &Args) -> Result<(Vec<InputResults>, Quarantine)> {
+    const INPUT: &str = "../data_builder/measurements.txt";
+    let paths = args.sources.paths(INPUT)?;
+    let mut inputs = Vec::with_capacity(paths.len());
+    let mut quarantine = Quarantine::default();
+    for path in paths.iter() {
+        let mut input = InputResults {
+            path: path.clone(),
+            stations: Stations::default(),
+            errors: ErrorReport::new(args.errors.on_error),
+        };
+        read_file(args, path, &mut input.stations, &mut input.errors, &mut quarantine).map_err(|e| {
+            // Say which input the error came from, if there's more than one
+            if paths.len() > 1 { e.context(format!("in {}", path.display())) } else { e }
+        })?;
+        inputs.push(input);
     }
-    i
+    Ok((inputs, quarantine))
 }
 
-fn read_file() -> Result<FxHashMap<String, StationReadings>> {
-    let mut result: FxHashMap<String, StationReadings> = FxHashMap::default();
+fn read_file(args: &Args, path: &Path, result: &mut Stations, errors: &mut ErrorReport, quarantine: &mut Quarantine) -> Result<()> {
+    if is_stream(path) {
+        // A pipe or a compressed file can't be mapped, so read it a block at a time instead.
+        // Reading is single threaded, but decompressing needn't be.
+        let block_size = args.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+        let threads = args.threads.workers(0);
+        let options = BlockOptions { backend: Backend::Pread, block_size, queue_depth: 1, direct: false, threads };
+        let mut blocks = Blocks::open(path, options)?;
+        read_pieces(args, blocks.by_ref(), result, errors, quarantine)?;
+        blocks.verify()?;
+    } else if let Some(budget) = args.window.memory_budget {
+        // Only map a window of the file at a time. A window is unmapped as soon as we're
+        // done with it.
+        let mut windows = Windows::open(path, budget, !args.input.no_mmap)?;
+        read_pieces(args, windows.by_ref(), result, errors, quarantine)?;
+        windows.verify()?;
+    } else {
+        let memory_map = args.input.open(path, args.threads.workers(0))?; // It's now a big sea of bytes!
+        args.advice.apply(&memory_map);
+        let schema = args.schema.schema(&memory_map).map_err(anyhow::Error::msg)?;
+        let records = Records::new(&memory_map, 0).strict(args.errors.strict).schema(schema);
+        read_records(args, records, 0, result, errors, quarantine)?;
 
-    let file = File::open("../data_builder/measurements.txt")?;
-    let memory_map = unsafe { memmap::Mmap::map(&file)? }; // It's now a big sea of bytes!
+        // If the file changed underneath us, the results are meaningless
+        memory_map.verify()?;
+    }
 
-    let mut index = 0;
-    while index < memory_map.len() {
-        let start = index; // Where did we start?
+    Ok(())
+}
 
-        // Find the first string
-        let i = find_next(&memory_map, start, ';');
-        let station = std::str::from_utf8(&memory_map[start .. i])?;
+/// Reads each record-aligned piece of the input in turn, keeping line numbers counting on from
+/// one piece to the next.
+fn read_pieces<P: Piece>(
+    args: &Args,
+    pieces: impl Iterator<Item = std::io::Result<P>>,
+    result: &mut Stations,
+    errors: &mut ErrorReport,
+    quarantine: &mut Quarantine,
+) -> Result<()> {
+    let mut found_schema = None;
+    let mut lines_before = 0;
+    for piece in pieces {
+        let piece = piece?;
+        args.advice.apply(&piece);
+        // Any header is in the first piece
+        let schema = match found_schema {
+            Some(schema) => schema,
+            None => *found_schema.insert(args.schema.schema(&piece).map_err(anyhow::Error::msg)?),
+        };
+        let records = Records::new(&piece, piece.offset()).strict(args.errors.strict).schema(schema);
+        lines_before += read_records(args, records, lines_before, result, errors, quarantine)?;
+    }
+    Ok(())
+}
 
-        // Find the second string
-        let start = i + 1;
-        let i = find_next(&memory_map, start, '\n');
-        let temperature = std::str::from_utf8(&memory_map[start..i])?;
-        let temperature = temperature.parse::<f32>()?;
-        index = i + 1;
+/// Adds up the readings from `records`, which start after `lines_before` lines of input, and
+/// returns how many lines there were.
+fn read_records(
+    args: &Args,
+    mut records: Records,
+    lines_before: u64,
+    result: &mut Stations,
+    errors: &mut ErrorReport,
+    quarantine: &mut Quarantine,
+) -> Result<u64> {
+    let values = args.values.filter();
+    let scale = args.scale.scale;
+    let empty = args.stats.accumulator();
+    for record in records.by_ref() {
+        // Split the line at the semicolon, and parse both halves
+        let reading = record.and_then(|record| Ok((record.station.clone(), record.value_checked(scale, &values)?)));
+        let (station, temperature) = match reading {
+            Ok((station, Some(temperature))) => (station, temperature),
+            Ok((station, None)) => {
+                quarantine.exclude(&station);
+                continue;
+            }
+            Err(mut error) => {
+                error.line += lines_before;
+                errors.record(error)?;
+                continue;
+            }
+        };
 
-        if let Some(result) = result.get_mut(station) {
-             result.max = f32::max(result.max, temperature);
-             result.min = f32::min(result.min, temperature);
-             result.sum += temperature;
-             result.count += 1;
+        if let Some(result) = result.get_mut(station.as_ref()) {
+             result.add(temperature);
          } else {
-             result.insert(station.to_string(), StationReadings {
-                 min: temperature,
-                 max: temperature,
-                 sum: temperature,
-                 count: 1,
-             });
+             let mut readings = empty.clone();
+             readings.add(temperature);
+             result.insert(station.to_string(), readings);
          }
     }
-    Ok(result)
+    Ok(records.lines())
 }
 
 struct Reading {
     station: String,
-    min: f32,
-    max: f32,
-    mean: f32,
+    min: Fixed,
+    max: Fixed,
+    mean: Fixed,
+    spread: Option<Spread>,
+    percentiles: Option<Percentiles>,
+    ranked: Option<String>,
 }
 
-fn calculate(readings: FxHashMap<String, StationReadings>) -> Result<Vec<Reading>> {
+fn calculate(readings: Stations, scale: Scale, stats: &StatsArgs, rank: &RankArgs) -> Result<Vec<Reading>> {
     let mut result = vec![];
+    let wanted = stats.percentiles();
+    let mut readings: Vec<_> = readings.into_iter().collect();
+    rank.rank(&mut readings, |(station, readings)| (station, readings));
     readings.into_iter().for_each(|(station, readings)| {
-        let mean = readings.sum / readings.count as f32;
-        result.push(Reading { station, min: readings.min, max: readings.max, mean });
+        let (min, max) = (scale.format(readings.min as i64), scale.format(readings.max as i64));
+        let spread = stats.stddev.then(|| readings.spread(scale));
+        let percentiles = readings.percentiles(&wanted, scale);
+        let ranked = rank.measure(&readings, scale);
+        result.push(Reading { station, min, max, mean: scale.format(readings.mean()), spread, percentiles, ranked });
     });
     Ok(result)
 }
 
 fn print_results(readings: Vec<Reading>) {
     for reading in readings {
-        println!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        print!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        if let Some(spread) = reading.spread {
+            print!("{spread};");
+        }
+        if let Some(percentiles) = reading.percentiles {
+            print!("{percentiles};");
+        }
+        if let Some(ranked) = reading.ranked {
+            print!("{ranked};");
+        }
+        println!();
     }
 }
 
@@ -91,32 +244,65 @@ macro_rules! time_it {
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    // This thread does all the reading, so it's the one to pin
+    args.threads.pin(0);
+
     // Setup timers
     let file_reader_time;
     let calculate_time;
     let print_time;
 
-    // Read the file, row by row into a vector
-    let stations = time_it!({
-        read_file()?
+    // Read the files, row by row into a vector
+    let faults = PageFaults::now();
+    let (inputs, quarantine) = time_it!({
+        read_files(&args)?
     }, file_reader_time);
 
-    // Calculate min, max and mean for each station
-    let readings = time_it!({
-        calculate(stations)?
+    // Add the inputs together, then calculate min, max and mean for each station, overall and
+    // for each input if asked
+    let (readings, per_input) = time_it!({
+        let mut combined = Stations::default();
+        for input in &inputs {
+            merge(&mut combined, input.stations.clone());
+        }
+        let mut per_input = vec![];
+        if args.sources.per_file {
+            for input in &inputs {
+                per_input.push((&input.path, calculate(input.stations.clone(), args.scale.scale, &args.stats, &args.rank)?));
+            }
+        }
+        (calculate(combined, args.scale.scale, &args.stats, &args.rank)?, per_input)
     }, calculate_time);
 
     // Print the results
     time_it!({
         print_results(readings);
+        for (path, readings) in per_input {
+            println!();
+            println!("{}:", path.display());
+            print_results(readings);
+        }
     }, print_time);
 
+    for input in &inputs {
+        if input.errors.skipped() > 0 {
+            if inputs.len() > 1 {
+                eprintln!("{}:", input.path.display());
+            }
+            eprintln!("{}", input.errors);
+        }
+    }
+    if quarantine.total() > 0 {
+        eprintln!("{quarantine}");
+    }
 
     println!("-----------------------------------------");
     println!("File & hash time: {:.3}s", file_reader_time);
     println!("Calculate time:   {:.3}s", calculate_time);
     println!("Print time:       {:.3}s", print_time);
     println!("TOTAL:            {:.3}s", file_reader_time + calculate_time + print_time);
+    println!("Page faults:      {}", PageFaults::since(faults));
 
     Ok(())
 }
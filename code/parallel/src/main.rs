@@ -1,136 +1,464 @@
-use std::fs::File;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::thread::available_parallelism;
 use anyhow::Result;
+use brc::ascii::{Fixed, Scale};
+use brc::chunks::{ChunkErrors, ChunkQueue, ChunkReport};
+use brc::blocks::Blocks;
+use brc::cli::{AdviceArgs, BackendArgs, ChunkArgs, ErrorArgs, ExportArgs, InputArgs, PrefetchArgs, RankArgs, RegionArgs, ScaleArgs, StatsArgs, SourcesArgs, ThreadArgs, TimeArgs, ValueArgs, SchemaArgs, WindowArgs};
+use brc::error::{ErrorReport, ParseError};
+use brc::input::{Piece, Windows};
+use brc::mapping::{prefetch, PageFaults};
+use brc::ranking;
+use brc::scan::Records;
+use brc::schema::Schema;
+use brc::histogram::{Percentile, Percentiles};
+use brc::stats::{Accumulator, Spread};
+use brc::timeseries::{TimeSeries, TimeWindows, Timestamp};
+use brc::validate::Quarantine;
+use clap::Parser;
 use rustc_hash::FxHashMap;
 
-#[derive(Default)]
-struct StationReadings {
-    min: f32,
-    max: f32,
-    sum: f32,
-    count: usize,
+type Stations = FxHashMap<String, Accumulator>;
+
+/// Adds the readings in `other` to `result`.
+fn merge(result: &mut Stations, other: Stations) {
+    for (station, readings) in other {
+        if let Some(result) = result.get_mut(&station) {
+            result.merge(&readings);
+        } else {
+            result.insert(station, readings);
+        }
+    }
+}
+
+/// Everything read from an input, or part of one.
+#[derive(Default, Clone)]
+struct Results {
+    stations: Stations,
+    /// Only filled in if time windows were asked for.
+    series: TimeSeries,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        merge(&mut self.stations, other.stations);
+        self.series.merge(other.series);
+    }
+}
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    sources: SourcesArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    window: WindowArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    schema: SchemaArgs,
+
+    #[command(flatten)]
+    scale: ScaleArgs,
+
+    #[command(flatten)]
+    values: ValueArgs,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    #[command(flatten)]
+    rank: RankArgs,
+
+    #[command(flatten)]
+    export: ExportArgs,
+
+    #[command(flatten)]
+    time: TimeArgs,
+
+    #[command(flatten)]
+    regions: RegionArgs,
+
+    #[command(flatten)]
+    chunks: ChunkArgs,
+
+    #[command(flatten)]
+    threads: ThreadArgs,
+
+    #[command(flatten)]
+    advice: AdviceArgs,
+
+    #[command(flatten)]
+    prefetch: PrefetchArgs,
 }
 
-fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
-    let mut i = start;
-    while memory_map[i] != character as u8 {
-        i += 1;
+/// What was found in one input.
+struct InputResults {
+    path: PathBuf,
+    results: Results,
+    errors: ErrorReport,
+}
+
+/// Says which input an error came from, if there's more than one.
+fn in_input(error: impl Into<anyhow::Error>, path: &Path, inputs: usize) -> anyhow::Error {
+    let error = error.into();
+    if inputs > 1 {
+        error.context(format!("in {}", path.display()))
+    } else {
+        error
     }
-    i
-}
-
-fn read_file() -> Result<FxHashMap<String, StationReadings>> {
-    let num_cpus = available_parallelism()?.get();
-    let mut result: FxHashMap<String, StationReadings> = FxHashMap::default();
-
-    let file = File::open("../data_builder/measurements.txt")?;
-    let memory_map = unsafe { memmap::Mmap::map(&file)? }; // It's now a big sea of bytes!
-
-    // Split the memory map into chunks of roughly equal size
-    let chunk_size = memory_map.len() / num_cpus;
-
-    // BUT - chunks are probably not aligned to record boundaries/lines. So for each chunk, we'll
-    // need to expand to the next newline character.
-    let mut chunk_indices = vec![0];
-    let mut counter = chunk_size;
-    for _ in 1 .. num_cpus {
-        let chunk_starts_at = find_next(
-            &memory_map,
-            counter,
-            '\n'
-        );
-        chunk_indices.push(chunk_starts_at);
-        counter = chunk_starts_at + chunk_size + 1;
+}
+
+fn read_files(args: &Args) -> Result<(Vec<InputResults>, Quarantine)> {
+    const INPUT: &str = "../data_builder/measurements.txt";
+    let paths = args.sources.paths(INPUT)?;
+    let windows = args.time.windows().map_err(anyhow::Error::msg)?;
+    let threads = args.threads.workers(0);
+    let mut quarantine = Quarantine::default();
+    let mut results: Vec<InputResults> = paths
+        .iter()
+        .map(|path| InputResults {
+            path: path.clone(),
+            results: Results::default(),
+            errors: ErrorReport::new(args.errors.on_error),
+        })
+        .collect();
+
+    // Every input that can be mapped is mapped up front and read in one go, so the workers
+    // move straight on from one file to the next instead of waiting for the slowest of them.
+    let mapped: Vec<usize> = (0..paths.len())
+        .filter(|&i| args.backend.blocks(&paths[i], threads).is_none() && args.window.memory_budget.is_none())
+        .collect();
+    let inputs = mapped
+        .iter()
+        .map(|&i| args.input.open(&paths[i], threads).map_err(|e| in_input(e, &paths[i], paths.len())))
+        .collect::<Result<Vec<_>>>()?; // It's now a big sea of bytes!
+    let parts = inputs
+        .iter()
+        .zip(&mapped)
+        .map(|(input, &i)| {
+            let schema = args.schema.schema(input).map_err(|e| in_input(anyhow::Error::msg(e), &paths[i], paths.len()))?;
+            Ok(Part { buffer: input, base_offset: 0, schema })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut found = vec![Results::default(); parts.len()];
+    let outcomes = read_chunks(args, windows, &parts, &mut found, &mut quarantine);
+    for (((&i, outcome), input), found) in mapped.iter().zip(outcomes).zip(&inputs).zip(found) {
+        let (errors, _) = outcome.map_err(|e| in_input(e, &paths[i], paths.len()))?;
+        // If the file changed underneath us, the results are meaningless
+        input.verify()?;
+        results[i].results = found;
+        results[i].errors = errors;
     }
 
-    // Now we can spawn threads to process each chunk. We'll use scoped threads to make
+    // The rest are read a piece at a time.
+    for (i, path) in paths.iter().enumerate().filter(|(i, _)| !mapped.contains(i)) {
+        results[i].errors = read_in_pieces(args, windows, path, threads, &mut results[i].results, &mut quarantine)
+            .map_err(|e| in_input(e, path, paths.len()))?;
+    }
+
+    Ok((results, quarantine))
+}
+
+fn read_in_pieces(
+    args: &Args,
+    windows: Option<TimeWindows>,
+    path: &Path,
+    threads: usize,
+    results: &mut Results,
+    quarantine: &mut Quarantine,
+) -> Result<ErrorReport> {
+    if let Some(options) = args.backend.blocks(path, threads) {
+        // Read the input into a few reusable buffers on a background thread, and put each
+        // block through the threaded reader as it arrives. This is the only way to read a pipe.
+        let mut blocks = Blocks::open(path, options)?;
+        let errors = read_pieces(args, windows, blocks.by_ref(), results, quarantine)?;
+        blocks.verify()?;
+        Ok(errors)
+    } else {
+        // Only map a window of the file at a time, and put each one through the same
+        // threaded reader. A window is unmapped as soon as we're done with it.
+        let budget = args.window.memory_budget.expect("mapped inputs are read in one go");
+        let mut pieces = Windows::open(path, budget, !args.input.no_mmap)?;
+        let errors = read_pieces(args, windows, pieces.by_ref(), results, quarantine)?;
+        pieces.verify()?;
+        Ok(errors)
+    }
+}
+
+/// Puts each record-aligned piece of the input through [`read_chunks`] in turn, keeping line
+/// numbers counting on from one piece to the next.
+fn read_pieces<P: Piece>(
+    args: &Args,
+    windows: Option<TimeWindows>,
+    pieces: impl Iterator<Item = io::Result<P>>,
+    results: &mut Results,
+    quarantine: &mut Quarantine,
+) -> Result<ErrorReport> {
+    let mut errors = ErrorReport::new(args.errors.on_error);
+    let mut found_schema = None;
+    let mut lines_before = 0;
+    for piece in pieces {
+        let piece = piece?;
+        // Any header is in the first piece
+        let schema = match found_schema {
+            Some(schema) => schema,
+            None => *found_schema.insert(args.schema.schema(&piece).map_err(anyhow::Error::msg)?),
+        };
+        let part = Part { buffer: &piece, base_offset: piece.offset(), schema };
+        let outcome = read_chunks(args, windows, &[part], std::slice::from_mut(results), quarantine).pop().unwrap();
+        let (piece_errors, lines) = outcome.map_err(|mut error| {
+            error.line += lines_before;
+            error
+        })?;
+        errors.merge(piece_errors, lines_before);
+        lines_before += lines;
+    }
+    Ok(errors)
+}
+
+/// A record-aligned buffer for the workers to read: a whole input, or a piece of one.
+struct Part<'a> {
+    buffer: &'a [u8],
+    /// Where `buffer` starts in its input.
+    base_offset: u64,
+    schema: Schema,
+}
+
+/// Reads `parts` on all the worker threads, adding what they found in each to the matching
+/// entry of `results` (and to its time series, if there are `windows`), and to `quarantine`.
+/// Returns the bad lines and the number of lines read in each part.
+fn read_chunks(
+    args: &Args,
+    windows: Option<TimeWindows>,
+    parts: &[Part],
+    results: &mut [Results],
+    quarantine: &mut Quarantine,
+) -> Vec<Result<(ErrorReport, u64), ParseError>> {
+    let num_cpus = args.threads.workers(0);
+    let on_error = args.errors.on_error;
+    let strict = args.errors.strict;
+    let mut errors: Vec<ChunkErrors> = parts.iter().map(|_| ChunkErrors::new(on_error)).collect();
+    let values = args.values.filter();
+    let scale = args.scale.scale;
+    let empty = args.export.accumulator(&args.stats);
+
+    // Split each buffer into chunks that each end on a record boundary. Each thread keeps
+    // taking the next chunk until there are none left, so a slow thread doesn't hold up the
+    // rest, and then moves on to the next buffer.
+    let queues: Vec<ChunkQueue> = parts
+        .iter()
+        .map(|part| {
+            args.advice.apply(part.buffer);
+            args.chunks.queue(part.buffer, num_cpus)
+        })
+        .collect();
+
+    // Now we can spawn threads to process the chunks. We'll use scoped threads to make
     // it easier to manage the lifetimes of the threads.
+    let stop_prefetching = AtomicBool::new(false);
     thread::scope(|scope| {
+        // Optionally fault pages in ahead of the workers, so they find them already resident.
+        if args.prefetch.prefetch {
+            let (queues, stop) = (&queues, &stop_prefetching);
+            scope.spawn(move || {
+                for (part, queue) in parts.iter().zip(queues) {
+                    prefetch(part.buffer, queue, args.prefetch.prefetch_ahead, stop);
+                }
+            });
+        }
+
         let mut handles = vec![];
         for cpu in 0 .. num_cpus {
             // Start by acquiring our own copy of variables to move.
-            let memory_map = &memory_map; // We're only moving the pointer, not the data
-            let chunk_start = chunk_indices[cpu];
-            let chunk_end = if cpu == num_cpus - 1 {
-                memory_map.len()
-            } else {
-                chunk_indices[cpu + 1]
-            };
-            let handle = scope.spawn(move || {
-                let mut local_result: FxHashMap<String, StationReadings> = FxHashMap::default();
-                let mut index = chunk_start;
-                while index < chunk_end {
-                    let start = index; // Where did we start?
-
-                    // Find the first string
-                    let i = find_next(&memory_map, start, ';');
-                    let station = std::str::from_utf8(&memory_map[start .. i]).unwrap();
-
-                    // Find the second string
-                    let start = i + 1;
-                    let i = find_next(&memory_map, start, '\n');
-                    let temperature = std::str::from_utf8(&memory_map[start..i]).unwrap();
-                    let temperature = temperature.parse::<f32>().unwrap();
-                    index = i + 1;
-
-                    if let Some(result) = local_result.get_mut(station) {
-                        result.max = f32::max(result.max, temperature);
-                        result.min = f32::min(result.min, temperature);
-                        result.sum += temperature;
-                        result.count += 1;
-                    } else {
-                        local_result.insert(station.to_string(), StationReadings {
-                            min: temperature,
-                            max: temperature,
-                            sum: temperature,
-                            count: 1,
-                        });
+            let queues = &queues;
+            let threads = &args.threads;
+            let (empty, windows) = (&empty, &windows);
+            let handle = scope.spawn(move || -> Result<_, (usize, ParseError)> {
+                threads.pin(cpu);
+                let mut local_results = vec![Results::default(); parts.len()];
+                let mut local_quarantine = Quarantine::default();
+                let mut reports: Vec<Vec<ChunkReport>> = parts.iter().map(|_| Vec::new()).collect();
+                for (part_index, (part, queue)) in parts.iter().zip(queues).enumerate() {
+                    let (buffer, schema) = (part.buffer, part.schema);
+                    let local_result = &mut local_results[part_index];
+                    for (index, chunk) in queue.worker(cpu) {
+                        let mut local_errors = ErrorReport::new(on_error);
+                        let mut records = Records::new(&buffer[chunk.clone()], part.base_offset + chunk.start as u64)
+                            .strict(strict)
+                            .schema(schema);
+                        for record in records.by_ref() {
+                            // Split the line at the semicolon, and parse both halves (and the
+                            // timestamp, if we need it)
+                            let reading = record.and_then(|record| {
+                                let time = windows.map(|_| record.timestamp()).transpose()?;
+                                Ok((record.station.clone(), time, record.value_checked(scale, &values)?))
+                            });
+                            let (station, time, temperature) = match reading {
+                                Ok((station, time, Some(temperature))) => (station, time, temperature),
+                                Ok((station, _, None)) => {
+                                    local_quarantine.exclude(&station);
+                                    continue;
+                                }
+                                Err(error) => {
+                                    local_errors.record(error).map_err(|error| {
+                                        queues.iter().for_each(ChunkQueue::cancel);
+                                        (part_index, queue.locate(buffer, index, error))
+                                    })?;
+                                    continue;
+                                }
+                            };
+
+                            if let Some(result) = local_result.stations.get_mut(station.as_ref()) {
+                                result.add(temperature);
+                            } else {
+                                let mut readings = empty.clone();
+                                readings.add(temperature);
+                                local_result.stations.insert(station.to_string(), readings);
+                            }
+                            if let (Some(windows), Some(time)) = (windows, time) {
+                                local_result.series.add(&station, time, temperature, windows, empty);
+                            }
+                        }
+                        reports[part_index].push(ChunkReport { index, errors: local_errors, lines: records.lines() });
                     }
                 }
 
-                local_result
+                Ok((local_results, local_quarantine, reports))
             }); // End thread
             handles.push(handle);
         }
 
+        // Threads finish in any order; the bad lines are put back in file order at the end.
         for handle in handles {
-            let local_result = handle.join().unwrap();
-            for (station, readings) in local_result {
-                if let Some(result) = result.get_mut(&station) {
-                    result.min = f32::min(result.min, readings.min);
-                    result.max = f32::max(result.max, readings.max);
-                    result.sum += readings.sum;
-                    result.count += readings.count;
-                } else {
-                    result.insert(station, readings);
+            let (local_results, local_quarantine, reports) = match handle.join().unwrap() {
+                Ok(local) => local,
+                Err((part, error)) => {
+                    errors[part].fail(error);
+                    continue;
                 }
+            };
+            for (errors, reports) in errors.iter_mut().zip(reports) {
+                errors.add(reports);
+            }
+            quarantine.merge(local_quarantine);
+            for (result, local_result) in results.iter_mut().zip(local_results) {
+                result.merge(local_result);
             }
         }
+        stop_prefetching.store(true, Ordering::Relaxed);
     });
 
-    Ok(result)
+    errors
+        .into_iter()
+        .map(|errors| {
+            let lines = errors.lines();
+            Ok((errors.finish()?, lines))
+        })
+        .collect()
 }
 
 struct Reading {
     station: String,
-    min: f32,
-    max: f32,
-    mean: f32,
+    min: Fixed,
+    max: Fixed,
+    mean: Fixed,
+    spread: Option<Spread>,
+    percentiles: Option<Percentiles>,
+    ranked: Option<String>,
 }
 
-fn calculate(readings: FxHashMap<String, StationReadings>) -> Result<Vec<Reading>> {
-    let mut result = vec![];
-    readings.into_iter().for_each(|(station, readings)| {
-        let mean = readings.sum / readings.count as f32;
-        result.push(Reading { station, min: readings.min, max: readings.max, mean });
-    });
-    Ok(result)
+impl Reading {
+    fn new(station: String, readings: &Accumulator, scale: Scale, stats: &StatsArgs, wanted: &[Percentile]) -> Self {
+        let (min, max) = (scale.format(readings.min as i64), scale.format(readings.max as i64));
+        let spread = stats.stddev.then(|| readings.spread(scale));
+        let percentiles = readings.percentiles(wanted, scale);
+        Reading { station, min, max, mean: scale.format(readings.mean()), spread, percentiles, ranked: None }
+    }
+}
+
+/// Everything but the station name, each followed by a semicolon.
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{};{};{};", self.min, self.max, self.mean)?;
+        if let Some(spread) = &self.spread {
+            write!(f, "{spread};")?;
+        }
+        if let Some(percentiles) = &self.percentiles {
+            write!(f, "{percentiles};")?;
+        }
+        if let Some(ranked) = &self.ranked {
+            write!(f, "{ranked};")?;
+        }
+        Ok(())
+    }
 }
 
-fn print_results(readings: Vec<Reading>) {
+fn calculate(readings: Stations, scale: Scale, stats: &StatsArgs, rank: &RankArgs) -> Result<Vec<Reading>> {
+    let wanted = stats.percentiles();
+    let mut readings: Vec<_> = readings.into_iter().collect();
+    rank.rank(&mut readings, |(station, readings)| (station, readings));
+    Ok(readings
+        .into_iter()
+        .map(|(station, readings)| Reading {
+            ranked: rank.measure(&readings, scale),
+            ..Reading::new(station, &readings, scale, stats, &wanted)
+        })
+        .collect())
+}
+
+/// The readings of each group of stations, in the order given unless they're to be ranked.
+/// --top only picks stations, so every group is kept either way.
+fn calculate_groups<'a>(
+    groups: impl Iterator<Item = (&'a str, &'a Accumulator)>,
+    scale: Scale,
+    stats: &StatsArgs,
+    rank: &RankArgs,
+) -> Vec<Reading> {
+    let wanted = stats.percentiles();
+    let mut groups: Vec<_> = groups.collect();
+    if rank.rank_by.is_some() {
+        ranking::rank(&mut groups, rank.rank_by, None, |&(group, readings)| (group, readings));
+    }
+    groups
+        .into_iter()
+        .map(|(group, readings)| Reading {
+            ranked: rank.measure(readings, scale),
+            ..Reading::new(group.to_string(), readings, scale, stats, &wanted)
+        })
+        .collect()
+}
+
+/// Each station's readings in each window, in order of station and then time.
+fn calculate_series(series: &TimeSeries, scale: Scale, stats: &StatsArgs) -> Vec<(Timestamp, Reading)> {
+    let wanted = stats.percentiles();
+    series
+        .iter()
+        .map(|(station, start, readings)| (start, Reading::new(station.to_string(), readings, scale, stats, &wanted)))
+        .collect()
+}
+
+fn print_results(readings: &[Reading]) {
     for reading in readings {
-        println!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        println!("{};{reading}", reading.station);
+    }
+}
+
+/// Prints a time series for each station, a line per window, headed by when the window starts.
+fn print_series(series: Vec<(Timestamp, Reading)>) {
+    for (start, reading) in series {
+        println!("{};{start};{reading}", reading.station);
     }
 }
 
@@ -147,32 +475,95 @@ macro_rules! time_it {
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    let regions = args.regions.regions()?;
+
     // Setup timers
     let file_reader_time;
     let calculate_time;
     let print_time;
 
-    // Read the file, row by row into a vector
-    let stations = time_it!({
-        read_file()?
+    // Read the files, row by row into a vector
+    let faults = PageFaults::now();
+    let (inputs, quarantine) = time_it!({
+        read_files(&args)?
     }, file_reader_time);
 
-    // Calculate min, max and mean for each station
-    let readings = time_it!({
-        calculate(stations)?
+    // Add the inputs together, then calculate min, max and mean for each station, overall and
+    // for each input if asked
+    let (readings, series, per_input, rollup, sparklines) = time_it!({
+        let mut combined = Results::default();
+        for input in &inputs {
+            combined.merge(input.results.clone());
+        }
+        let series = calculate_series(&combined.series, args.scale.scale, &args.stats);
+        let combined = combined.stations;
+        let mut stations: Vec<(&str, &Accumulator)> =
+            combined.iter().map(|(station, readings)| (station.as_str(), readings)).collect();
+        stations.sort_unstable_by_key(|(station, _)| *station);
+        let sparklines = args.export.export(&stations, args.scale.scale)?;
+        let rollup = regions.map(|regions| {
+            let rollup = regions.roll_up(stations.iter().copied());
+            let regions = calculate_groups(rollup.regions(), args.scale.scale, &args.stats, &args.rank);
+            let countries = calculate_groups(rollup.countries(), args.scale.scale, &args.stats, &args.rank);
+            (regions, countries, rollup.unmapped_stations())
+        });
+        let mut per_input = vec![];
+        if args.sources.per_file {
+            for input in &inputs {
+                per_input.push((&input.path, calculate(input.results.stations.clone(), args.scale.scale, &args.stats, &args.rank)?));
+            }
+        }
+        (calculate(combined, args.scale.scale, &args.stats, &args.rank)?, series, per_input, rollup, sparklines)
     }, calculate_time);
 
     // Print the results
     time_it!({
-        print_results(readings);
+        print_results(&readings);
+        for (path, readings) in per_input {
+            println!();
+            println!("{}:", path.display());
+            print_results(&readings);
+        }
+        if let Some((regions, countries, _)) = rollup.as_ref() {
+            println!();
+            println!("Regions:");
+            print_results(regions);
+            println!();
+            println!("Countries:");
+            print_results(countries);
+        }
+        if !series.is_empty() {
+            println!();
+            print_series(series);
+        }
+        if let Some(sparklines) = sparklines {
+            println!();
+            println!("{sparklines}");
+        }
     }, print_time);
 
+    for input in &inputs {
+        if input.errors.skipped() > 0 {
+            if inputs.len() > 1 {
+                eprintln!("{}:", input.path.display());
+            }
+            eprintln!("{}", input.errors);
+        }
+    }
+    if quarantine.total() > 0 {
+        eprintln!("{quarantine}");
+    }
+    if let Some((_, _, unmapped @ 1..)) = rollup {
+        eprintln!("{unmapped} station(s) aren't in the table of regions");
+    }
 
     println!("-----------------------------------------");
     println!("File & hash time: {:.3}s", file_reader_time);
     println!("Calculate time:   {:.3}s", calculate_time);
     println!("Print time:       {:.3}s", print_time);
     println!("TOTAL:            {:.3}s", file_reader_time + calculate_time + print_time);
+    println!("Page faults:      {}", PageFaults::since(faults));
 
     Ok(())
 }
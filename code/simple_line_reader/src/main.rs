@@ -1,28 +1,132 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufRead;
-use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use brc::ascii::{Fixed, Scale};
+use brc::cli::{ErrorArgs, RankArgs, ScaleArgs, SpillArgs, StatsArgs, SourcesArgs, ValueArgs, SchemaArgs};
+use brc::error::ErrorReport;
+use brc::lines::Lines;
+use brc::histogram::Percentiles;
+use brc::spill::Spill;
+use brc::stats::{Accumulator, Spread};
+use brc::validate::Quarantine;
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    sources: SourcesArgs,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    schema: SchemaArgs,
+
+    #[command(flatten)]
+    scale: ScaleArgs,
+
+    #[command(flatten)]
+    values: ValueArgs,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    #[command(flatten)]
+    rank: RankArgs,
+
+    #[command(flatten)]
+    spill: SpillArgs,
+}
 
 struct RawReading {
     station: String,
-    temperature: f32,
+    temperature: i32,
 }
 
-fn read_file() -> Result<Vec<RawReading>> {
-    let file = File::open("../data_builder/measurements.txt")?;
-    let reader = std::io::BufReader::new(file);
-    let mut result = vec![];
-    for line in reader.lines() {
+/// Every reading kept in memory, or with a memory limit, handed to a spill that sorts them out
+/// to disk.
+enum Rows {
+    InMemory(Vec<RawReading>),
+    Spilled(Spill),
+}
+
+impl Rows {
+    fn new(args: &Args) -> Result<Self> {
+        Ok(match args.spill.spill()? {
+            Some(spill) => Rows::Spilled(spill),
+            None => Rows::InMemory(vec![]),
+        })
+    }
+
+    fn add(&mut self, station: &str, temperature: i32) -> Result<()> {
+        match self {
+            Rows::InMemory(rows) => rows.push(RawReading { station: station.to_string(), temperature }),
+            Rows::Spilled(spill) => spill.add(station, temperature)?,
+        }
+        Ok(())
+    }
+}
+
+/// What was found in one input. Its rows are only kept apart from the rest with `--per-file`.
+struct InputResults {
+    path: PathBuf,
+    rows: Option<Rows>,
+    errors: ErrorReport,
+}
+
+fn read_files(args: &Args) -> Result<(Rows, Vec<InputResults>, Quarantine)> {
+    const INPUT: &str = "../data_builder/measurements.txt";
+    let paths = args.sources.paths(INPUT)?;
+    let mut combined = Rows::new(args)?;
+    let mut inputs = Vec::with_capacity(paths.len());
+    let mut quarantine = Quarantine::default();
+    for path in paths.iter() {
+        let mut input = InputResults {
+            path: path.clone(),
+            rows: if args.sources.per_file { Some(Rows::new(args)?) } else { None },
+            errors: ErrorReport::new(args.errors.on_error),
+        };
+        read_file(args, path, &mut combined, input.rows.as_mut(), &mut input.errors, &mut quarantine).map_err(|e| {
+            // Say which input the error came from, if there's more than one
+            if paths.len() > 1 { e.context(format!("in {}", path.display())) } else { e }
+        })?;
+        inputs.push(input);
+    }
+    Ok((combined, inputs, quarantine))
+}
+
+fn read_file(
+    args: &Args,
+    path: &Path,
+    result: &mut Rows,
+    mut own: Option<&mut Rows>,
+    errors: &mut ErrorReport,
+    quarantine: &mut Quarantine,
+) -> Result<()> {
+    let reader = brc::input::reader(path)?;
+    let (schema, reader) = args.schema.schema_of_reader(reader)?;
+    let values = args.values.filter();
+    let scale = args.scale.scale;
+    for line in Lines::new(reader).strict(args.errors.strict).schema(schema) {
         let line = line?;
-        let mut parts = line.split(';');
-        let station = parts.next().context("No next!")?.to_string();
-        let temperature = parts.next().context("No next!")?.parse::<f32>()?;
-        result.push(RawReading { station, temperature });
+        let reading = line.record().and_then(|record| {
+            Ok((record.station.to_string(), record.value_checked(scale, &values)?))
+        });
+        match reading {
+            Ok((station, Some(temperature))) => {
+                result.add(&station, temperature)?;
+                if let Some(own) = own.as_deref_mut() {
+                    own.add(&station, temperature)?;
+                }
+            }
+            Ok((station, None)) => quarantine.exclude(&station),
+            Err(error) => errors.record(error)?,
+        }
     }
-    Ok(result)
+    Ok(())
 }
 
-fn hash_file(readings: &[RawReading]) -> Result<HashMap<String, Vec<f32>>> {
+fn hash_file(readings: &[RawReading]) -> Result<HashMap<String, Vec<i32>>> {
     let result = readings.iter().fold(HashMap::new(), |mut acc, reading| {
         acc.entry(reading.station.clone()).or_insert(vec![]).push(reading.temperature);
         acc
@@ -32,26 +136,68 @@ fn hash_file(readings: &[RawReading]) -> Result<HashMap<String, Vec<f32>>> {
 
 struct Reading {
     station: String,
-    min: f32,
-    max: f32,
-    mean: f32,
+    min: Fixed,
+    max: Fixed,
+    mean: Fixed,
+    spread: Option<Spread>,
+    percentiles: Option<Percentiles>,
+    ranked: Option<String>,
 }
 
-fn calculate(readings: HashMap<String, Vec<f32>>) -> Result<Vec<Reading>> {
+fn calculate(readings: HashMap<String, Vec<i32>>, scale: Scale, stats: &StatsArgs, rank: &RankArgs) -> Result<Vec<Reading>> {
     let mut result = vec![];
-    readings.into_iter().for_each(|(station, mut readings)| {
-        let min = readings.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-        let max = readings.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-        let sum = readings.iter().sum::<f32>();
-        let mean = sum / readings.len() as f32;
-        result.push(Reading { station, min: *min, max: *max, mean });
+    let wanted = stats.percentiles();
+    let mut stations: Vec<(String, Accumulator)> = readings
+        .into_iter()
+        .map(|(station, readings)| {
+            let mut totals = stats.accumulator();
+            readings.iter().for_each(|&temperature| totals.add(temperature));
+            (station, totals)
+        })
+        .collect();
+    rank.rank(&mut stations, |(station, totals)| (station, totals));
+    stations.into_iter().for_each(|(station, totals)| {
+        let (min, max) = (scale.format(totals.min as i64), scale.format(totals.max as i64));
+        let spread = stats.stddev.then(|| totals.spread(scale));
+        let percentiles = totals.percentiles(&wanted, scale);
+        let ranked = rank.measure(&totals, scale);
+        result.push(Reading { station, min, max, mean: scale.format(totals.mean()), spread, percentiles, ranked });
     });
     Ok(result)
 }
 
+/// Like [`calculate`], for readings that were spilled to disk.
+fn calculate_spilled(spill: Spill, scale: Scale, stats: &StatsArgs, rank: &RankArgs) -> Result<Vec<Reading>> {
+    let wanted = stats.percentiles();
+    let mut stations = spill.finish(&wanted)?;
+    rank.rank(&mut stations, |station| (&station.name, &station.readings));
+    Ok(stations
+        .into_iter()
+        .map(|station| {
+            let totals = station.readings;
+            let (min, max) = (scale.format(totals.min as i64), scale.format(totals.max as i64));
+            let spread = stats.stddev.then(|| totals.spread(scale));
+            let percentiles = (!wanted.is_empty())
+                .then(|| Percentiles(station.percentiles.iter().map(|&value| scale.format(value as i64)).collect()));
+            let ranked = rank.measure(&totals, scale);
+            Reading { station: station.name, min, max, mean: scale.format(totals.mean()), spread, percentiles, ranked }
+        })
+        .collect())
+}
+
 fn print_results(readings: Vec<Reading>) {
     for reading in readings {
-        println!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        print!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        if let Some(spread) = reading.spread {
+            print!("{spread};");
+        }
+        if let Some(percentiles) = reading.percentiles {
+            print!("{percentiles};");
+        }
+        if let Some(ranked) = reading.ranked {
+            print!("{ranked};");
+        }
+        println!();
     }
 }
 
@@ -67,33 +213,80 @@ macro_rules! time_it {
     };
 }
 
+/// Hashes and calculates `rows`, adding the time each took to the timers.
+fn summarise(rows: Rows, args: &Args, hash_time: &mut f32, calculate_time: &mut f32) -> Result<Vec<Reading>> {
+    let (hashed, calculated);
+    let readings = match rows {
+        Rows::InMemory(rows) => {
+            // Hash the rows by station
+            let stations = time_it!({
+                hash_file(&rows)?
+            }, hashed);
+
+            // Calculate min, max and mean for each station
+            time_it!({
+                calculate(stations, args.scale.scale, &args.stats, &args.rank)?
+            }, calculated)
+        }
+        Rows::Spilled(spill) => {
+            // The spill has kept the rows by station all along, so there's nothing to hash;
+            // merging its sorted runs back together is the calculation
+            hashed = 0.0;
+            time_it!({
+                calculate_spilled(spill, args.scale.scale, &args.stats, &args.rank)?
+            }, calculated)
+        }
+    };
+    *hash_time += hashed;
+    *calculate_time += calculated;
+    Ok(readings)
+}
+
 fn main() -> Result<()> {
+    let args = Args::parse();
+
     // Setup timers
     let file_reader_time;
-    let hash_time;
-    let calculate_time;
     let print_time;
 
-    // Read the file, row by row into a vector
-    let rows = time_it!({
-        read_file()?
+    // Read the files, row by row into a vector
+    let (rows, inputs, quarantine) = time_it!({
+        read_files(&args)?
     }, file_reader_time);
 
-    // Hash the rows by station
-    let stations = time_it!({
-        hash_file(&rows)?
-    }, hash_time);
-
-    // Calculate min, max and mean for each station
-    let readings = time_it!({
-        calculate(stations)?
-    }, calculate_time);
+    let mut hash_time = 0.0;
+    let mut calculate_time = 0.0;
+    let readings = summarise(rows, &args, &mut hash_time, &mut calculate_time)?;
+    let mut per_input = vec![];
+    let mut errors = vec![];
+    for input in inputs {
+        if let Some(rows) = input.rows {
+            per_input.push((input.path.clone(), summarise(rows, &args, &mut hash_time, &mut calculate_time)?));
+        }
+        errors.push((input.path, input.errors));
+    }
 
     // Print the results
     time_it!({
         print_results(readings);
+        for (path, readings) in per_input {
+            println!();
+            println!("{}:", path.display());
+            print_results(readings);
+        }
     }, print_time);
 
+    for (path, input_errors) in &errors {
+        if input_errors.skipped() > 0 {
+            if errors.len() > 1 {
+                eprintln!("{}:", path.display());
+            }
+            eprintln!("{input_errors}");
+        }
+    }
+    if quarantine.total() > 0 {
+        eprintln!("{quarantine}");
+    }
 
     println!("-----------------------------------------");
     println!("File reader time: {:.3}s", file_reader_time);
@@ -1,46 +1,148 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufRead;
-use anyhow::{Context, Result};
-
-fn read_file() -> Result<HashMap<String, Vec<f32>>> {
-    let mut result = HashMap::new();
-    let file = File::open("../data_builder/measurements.txt")?;
-    let reader = std::io::BufReader::new(file);
-    for line in reader.lines() {
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use brc::ascii::{Fixed, Scale};
+use brc::cli::{ErrorArgs, RankArgs, ScaleArgs, StatsArgs, SourcesArgs, ValueArgs, SchemaArgs};
+use brc::error::ErrorReport;
+use brc::lines::Lines;
+use brc::histogram::Percentiles;
+use brc::stats::{Accumulator, Spread};
+use brc::validate::Quarantine;
+use clap::Parser;
+
+type Stations = HashMap<String, Vec<i32>>;
+
+/// Adds the readings in `other` to `result`.
+fn merge(result: &mut Stations, other: Stations) {
+    for (station, readings) in other {
+        result.entry(station).or_default().extend(readings);
+    }
+}
+
+/// What was found in one input.
+struct InputResults {
+    path: PathBuf,
+    stations: Stations,
+    errors: ErrorReport,
+}
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    sources: SourcesArgs,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    schema: SchemaArgs,
+
+    #[command(flatten)]
+    scale: ScaleArgs,
+
+    #[command(flatten)]
+    values: ValueArgs,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    #[command(flatten)]
+    rank: RankArgs,
+}
+
+fn read_files(args: &Args) -> Result<(Vec<InputResults>, Quarantine)> {
+    const INPUT: &str = "../data_builder/measurements.txt";
+    let paths = args.sources.paths(INPUT)?;
+    let mut inputs = Vec::with_capacity(paths.len());
+    let mut quarantine = Quarantine::default();
+    for path in paths.iter() {
+        let mut input = InputResults {
+            path: path.clone(),
+            stations: Stations::default(),
+            errors: ErrorReport::new(args.errors.on_error),
+        };
+        read_file(args, path, &mut input.stations, &mut input.errors, &mut quarantine).map_err(|e| {
+            // Say which input the error came from, if there's more than one
+            if paths.len() > 1 { e.context(format!("in {}", path.display())) } else { e }
+        })?;
+        inputs.push(input);
+    }
+    Ok((inputs, quarantine))
+}
+
+fn read_file(args: &Args, path: &Path, result: &mut Stations, errors: &mut ErrorReport, quarantine: &mut Quarantine) -> Result<()> {
+    let reader = brc::input::reader(path)?;
+    let (schema, reader) = args.schema.schema_of_reader(reader)?;
+    let values = args.values.filter();
+    let scale = args.scale.scale;
+    for line in Lines::new(reader).strict(args.errors.strict).schema(schema) {
         let line = line?;
-        let mut parts = line.split(';');
-        let station = parts.next().context("No next!")?.to_string();
-        let temperature = parts.next().context("No next!")?.parse::<f32>()?;
+        let reading = line.record().and_then(|record| {
+            Ok((record.station.to_string(), record.value_checked(scale, &values)?))
+        });
+        let (station, temperature) = match reading {
+            Ok((station, Some(temperature))) => (station, temperature),
+            Ok((station, None)) => {
+                quarantine.exclude(&station);
+                continue;
+            }
+            Err(error) => {
+                errors.record(error)?;
+                continue;
+            }
+        };
 
         let entry = result.entry(station).or_insert(vec![]);
         entry.push(temperature);
     }
-    Ok(result)
+    Ok(())
 }
 
 struct Reading {
     station: String,
-    min: f32,
-    max: f32,
-    mean: f32,
+    min: Fixed,
+    max: Fixed,
+    mean: Fixed,
+    spread: Option<Spread>,
+    percentiles: Option<Percentiles>,
+    ranked: Option<String>,
 }
 
-fn calculate(readings: HashMap<String, Vec<f32>>) -> Result<Vec<Reading>> {
+fn calculate(readings: Stations, scale: Scale, stats: &StatsArgs, rank: &RankArgs) -> Result<Vec<Reading>> {
     let mut result = vec![];
-    readings.into_iter().for_each(|(station, readings)| {
-        let min = readings.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-        let max = readings.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-        let sum = readings.iter().sum::<f32>();
-        let mean = sum / readings.len() as f32;
-        result.push(Reading { station, min: *min, max: *max, mean });
+    let wanted = stats.percentiles();
+    let mut stations: Vec<(String, Accumulator)> = readings
+        .into_iter()
+        .map(|(station, readings)| {
+            let mut totals = stats.accumulator();
+            readings.iter().for_each(|&temperature| totals.add(temperature));
+            (station, totals)
+        })
+        .collect();
+    rank.rank(&mut stations, |(station, totals)| (station, totals));
+    stations.into_iter().for_each(|(station, totals)| {
+        let (min, max) = (scale.format(totals.min as i64), scale.format(totals.max as i64));
+        let spread = stats.stddev.then(|| totals.spread(scale));
+        let percentiles = totals.percentiles(&wanted, scale);
+        let ranked = rank.measure(&totals, scale);
+        result.push(Reading { station, min, max, mean: scale.format(totals.mean()), spread, percentiles, ranked });
     });
     Ok(result)
 }
 
 fn print_results(readings: Vec<Reading>) {
     for reading in readings {
-        println!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        print!("{};{};{};{};", reading.station, reading.min, reading.max, reading.mean);
+        if let Some(spread) = reading.spread {
+            print!("{spread};");
+        }
+        if let Some(percentiles) = reading.percentiles {
+            print!("{percentiles};");
+        }
+        if let Some(ranked) = reading.ranked {
+            print!("{ranked};");
+        }
+        println!();
     }
 }
 
@@ -57,26 +159,55 @@ macro_rules! time_it {
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+
     // Setup timers
     let file_reader_time;
     let calculate_time;
     let print_time;
 
-    // Read the file, row by row into a vector
-    let stations = time_it!({
-        read_file()?
+    // Read the files, row by row into a vector
+    let (inputs, quarantine) = time_it!({
+        read_files(&args)?
     }, file_reader_time);
 
-    // Calculate min, max and mean for each station
-    let readings = time_it!({
-        calculate(stations)?
+    // Add the inputs together, then calculate min, max and mean for each station, overall and
+    // for each input if asked
+    let (readings, per_input) = time_it!({
+        let mut combined = Stations::default();
+        for input in &inputs {
+            merge(&mut combined, input.stations.clone());
+        }
+        let mut per_input = vec![];
+        if args.sources.per_file {
+            for input in &inputs {
+                per_input.push((&input.path, calculate(input.stations.clone(), args.scale.scale, &args.stats, &args.rank)?));
+            }
+        }
+        (calculate(combined, args.scale.scale, &args.stats, &args.rank)?, per_input)
     }, calculate_time);
 
     // Print the results
     time_it!({
         print_results(readings);
+        for (path, readings) in per_input {
+            println!();
+            println!("{}:", path.display());
+            print_results(readings);
+        }
     }, print_time);
 
+    for input in &inputs {
+        if input.errors.skipped() > 0 {
+            if inputs.len() > 1 {
+                eprintln!("{}:", input.path.display());
+            }
+            eprintln!("{}", input.errors);
+        }
+    }
+    if quarantine.total() > 0 {
+        eprintln!("{quarantine}");
+    }
 
     println!("-----------------------------------------");
     println!("File & hash time: {:.3}s", file_reader_time);
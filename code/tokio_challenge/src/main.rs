@@ -1,8 +1,20 @@
-use std::fs::File;
 use std::hash::Hasher;
+use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use std::thread::available_parallelism;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::mpsc::{self, Sender};
+use brc::blocks::{Backend, BlockOptions, Blocks, DEFAULT_BLOCK_SIZE};
+use brc::chunks::{ChunkErrors, ChunkReport};
+use brc::cli::{parse_size, AdviceArgs, ChunkArgs, ErrorArgs, InputArgs, PrefetchArgs, RankArgs, ScaleArgs, SchemaArgs, SourceArgs, StatsArgs, ThreadArgs};
+use brc::error::{ErrorReport, ParseError};
+use brc::input::{is_stream, Input, Piece};
+use brc::mapping::{prefetch, PageFaults};
+use brc::schema::Schema;
+use brc::scan::Records;
+use brc::stats::Accumulator;
+use anyhow::Context;
+use clap::Parser;
 use rustc_hash::{FxHashMap, FxHasher};
 
 #[inline(always)]
@@ -14,60 +26,10 @@ fn find_next(memory_map: &[u8], start: usize, character: char) -> usize {
     i
 }
 
-fn chunk_indices(memory_map: &[u8], num_cpus: usize) -> Vec<usize> {
-    // Split the memory map into chunks of roughly equal size
-    let chunk_size = memory_map.len() / num_cpus;
-
-    // BUT - chunks are probably not aligned to record boundaries/lines. So for each chunk, we'll
-    // need to expand to the next newline character.
-    let mut chunk_indices = vec![0];
-    let mut counter = chunk_size;
-    for _ in 1 .. num_cpus {
-        let chunk_starts_at = find_next(
-            &memory_map,
-            counter,
-            '\n'
-        );
-        chunk_indices.push(chunk_starts_at);
-        counter = chunk_starts_at + chunk_size + 1;
-    }
-    chunk_indices
-}
-
-fn ascii_slice_to_i32(buffer: &[u8]) -> i32 {
-    let size = buffer.len();
-    let mut negative_multiplier = 1;
-    let mut accumulator = 0;
-    let mut positional_mul = 10_i32.pow(size as u32 - 2);
-    for i in 0 .. size {
-        match buffer[i] {
-            b'-' => {
-                negative_multiplier = -1;
-                positional_mul /= 10;
-            }
-            b'.' => {
-                // Do nothing
-            }
-            48 ..= 57 => {
-                // Digits
-                let digit = buffer[i] as i32 - 48;
-                accumulator += digit * positional_mul;
-                positional_mul /= 10;
-            }
-            _ => panic!("Unhandled ASCII numerical symbol: {}", buffer[i]),
-        }
-    }
-    accumulator *= negative_multiplier;
-    accumulator
-}
-
 #[derive(Debug)]
 struct Station {
     name: String,
-    count: usize,
-    min: i32,
-    max: i32,
-    sum: i32,
+    readings: Accumulator,
 }
 
 fn hash_station_name(station_name: &[u8]) -> u64 {
@@ -76,126 +38,299 @@ fn hash_station_name(station_name: &[u8]) -> u64 {
     hasher.finish()
 }
 
-fn pre_hash_stations() -> anyhow::Result<FxHashMap<u64, Station>> {
+/// The stations we know the names of. The tasks only send hashes, so a station that isn't in
+/// here can't be named.
+const STATIONS: &str = "../data_builder/weather_stations.csv";
+
+fn pre_hash_stations(empty: &Accumulator) -> anyhow::Result<FxHashMap<u64, Station>> {
     let mut result = FxHashMap::default();
 
-    let file = File::open("../data_builder/weather_stations.csv")?;
-    let memory_map = unsafe { memmap::Mmap::map(&file)? };
+    let memory_map = Input::open(STATIONS).with_context(|| format!("couldn't read {STATIONS}"))?;
     let mut index = 0;
     while index < memory_map.len() {
         let first_semicolon = find_next(&memory_map, index, ';');
         let station_name = &memory_map[index .. first_semicolon];
 
         result.insert(hash_station_name(station_name), Station {
-            name: String::from_utf8_lossy(station_name).to_string(),
-            count: 0,
-            min: i32::MAX,
-            max: i32::MIN,
-            sum: 0,
+            name: std::str::from_utf8(station_name)?.to_string(),
+            readings: empty.clone(),
         });
 
         let end_of_line = find_next(&memory_map, first_semicolon, '\n');
         index = end_of_line + 1;
     }
+    memory_map.verify()?;
     Ok(result)
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let start = std::time::Instant::now();
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    source: SourceArgs,
 
-    // Count available CPUs (minus 1 for the receiver)
-    let num_cpus = available_parallelism()?.get() - 1;
-    //let num_cpus = available_parallelism()?.get() * 2;
-    //let num_cpus = available_parallelism()?.get() * 10;
+    #[command(flatten)]
+    input: InputArgs,
 
-    // Build the channel
-    let (tx, mut rx) = mpsc::channel::<Box<Vec<(u64, i32)>>>(4096);
+    /// How much of a pipe or compressed input, which can't be mapped, to read at a time, such
+    /// as 4M. Defaults to 16M
+    #[arg(long, value_parser = parse_size)]
+    block_size: Option<usize>,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    schema: SchemaArgs,
+
+    #[command(flatten)]
+    scale: ScaleArgs,
+
+    #[command(flatten)]
+    stats: StatsArgs,
+
+    #[command(flatten)]
+    rank: RankArgs,
+
+    #[command(flatten)]
+    chunks: ChunkArgs,
+
+    #[command(flatten)]
+    threads: ThreadArgs,
+
+    #[command(flatten)]
+    advice: AdviceArgs,
+
+    #[command(flatten)]
+    prefetch: PrefetchArgs,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Arc::new(Args::parse());
+
+    // Count available CPUs (minus 1 for the receiver, but always at least one worker). Try
+    // --threads with more tasks than CPUs to see how Tokio copes.
+    let num_cpus = args.threads.workers(1);
+
+    // Tasks aren't tied to threads, so any pinning applies to the runtime's threads instead.
+    let thread_number = AtomicUsize::new(0);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(num_cpus + 1)
+        .on_thread_start({
+            let args = args.clone();
+            move || args.threads.pin(thread_number.fetch_add(1, Ordering::Relaxed))
+        })
+        .enable_all()
+        .build()?;
+    runtime.block_on(run(&args, num_cpus))
+}
+
+/// A batch of readings, by station hash, on its way to the receiver.
+type Readings = Box<Vec<(u64, i32)>>;
+
+/// Sends the readings in `path` to the receiver, and returns the bad lines.
+async fn read_input(args: &Args, path: &Path, num_cpus: usize, tx: Sender<Readings>) -> anyhow::Result<ErrorReport> {
+    if !is_stream(path) {
+        // Memory map the file; anything compressed is streamed below instead
+        let memory_map = Arc::new(args.input.open(path, args.threads.workers(0))?);
+        args.advice.apply(&memory_map);
+        let schema = args.schema.schema(&memory_map).map_err(anyhow::Error::msg)?;
+        let outcome = read_chunks(args, memory_map.clone(), 0, 0, schema, num_cpus, &tx).await;
+        // If the file changed underneath us, the results are meaningless
+        memory_map.verify()?;
+        return Ok(outcome?.0);
+    }
+
+    // A pipe or a compressed file can't be mapped, so it's read a block at a time on a
+    // background thread, and each block goes through the tasks as it arrives.
+    let block_size = args.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let options = BlockOptions { backend: Backend::Pread, block_size, queue_depth: 1, direct: false, threads: args.threads.workers(0) };
+    let mut blocks = Blocks::open(path, options)?;
+    let mut errors = ErrorReport::new(args.errors.on_error);
+    let mut found_schema = None;
+    let mut lines_before = 0;
+    for block in blocks.by_ref() {
+        let block = block?;
+        args.advice.apply(&block);
+        // Any header is in the first block
+        let schema = match found_schema {
+            Some(schema) => schema,
+            None => *found_schema.insert(args.schema.schema(&block).map_err(anyhow::Error::msg)?),
+        };
+        let offset = block.offset();
+        let (block_errors, lines) = read_chunks(args, Arc::new(block), offset, lines_before, schema, num_cpus, &tx).await?;
+        errors.merge(block_errors, lines_before);
+        lines_before += lines;
+    }
+    blocks.verify()?;
+    Ok(errors)
+}
+
+/// Splits `buffer`, which starts `base_offset` bytes and `lines_before` lines into the input,
+/// between the calculation tasks, which send their readings down `tx`. Returns the bad lines
+/// and the number of lines read.
+async fn read_chunks<B>(
+    args: &Args,
+    buffer: Arc<B>,
+    base_offset: u64,
+    lines_before: u64,
+    schema: Schema,
+    num_cpus: usize,
+    tx: &Sender<Readings>,
+) -> anyhow::Result<(ErrorReport, u64)>
+where
+    B: Deref<Target = [u8]> + Send + Sync + 'static,
+{
+    let queue = Arc::new(args.chunks.queue(&buffer, num_cpus));
 
-    // Memory map the file
-    let file = File::open("../data_builder/measurements_1b.txt")?;
-    let memory_map = Arc::new(unsafe { memmap::Mmap::map(&file)? });
-    let chunk_indices = chunk_indices(&memory_map, num_cpus);
+    // Optionally fault pages in ahead of the tasks, on a thread of its own so it doesn't
+    // take a runtime thread away from them.
+    let stop_prefetching = Arc::new(AtomicBool::new(false));
+    if args.prefetch.prefetch {
+        let (buffer, queue, stop) = (buffer.clone(), queue.clone(), stop_prefetching.clone());
+        let ahead = args.prefetch.prefetch_ahead;
+        std::thread::spawn(move || prefetch(&buffer, &queue, ahead, &stop));
+    }
 
     // We're going to use Tokio tasks
-    let mut futures = Vec::with_capacity(num_cpus + 1);
+    let mut futures = Vec::with_capacity(num_cpus);
 
     // Spawn the calculation tasks
     for cpu in 0..num_cpus {
         // Thread-local for moving into the task
-        let memory_map = memory_map.clone(); // We're only moving the pointer, not the data
-        let chunk_start = chunk_indices[cpu];
-        let chunk_end = if cpu == num_cpus - 1 {
-            memory_map.len()
-        } else {
-            chunk_indices[cpu + 1]
-        };
+        let buffer = buffer.clone(); // We're only moving the pointer, not the data
+        let queue = queue.clone();
         let my_tx = tx.clone();
+        let on_error = args.errors.on_error;
+        let strict = args.errors.strict;
+        let scale = args.scale.scale;
 
         let future = tokio::spawn(async move {
-            let mut index = chunk_start;
+            let mut reports = Vec::new();
             const BUFFER_SIZE: usize = 1_000;
-            let mut buffer = Box::new(Vec::with_capacity(BUFFER_SIZE));
-            while index < chunk_end {
-                let start = index; // Where did we start?
-
-                // Find the first string
-                let i = find_next(&memory_map, start, ';');
-                let station_slice = &memory_map[start .. i];
-
-                // Find the second string
-                let start = i + 1;
-                let i = find_next(&memory_map, start, '\n');
-                let temperature = ascii_slice_to_i32(&memory_map[start..i]);
-
-                buffer.push((hash_station_name(station_slice), temperature));
-                if buffer.len() == BUFFER_SIZE {
-                    my_tx.send(buffer).await.unwrap();
-                    buffer = Box::new(Vec::with_capacity(BUFFER_SIZE));
-                }
+            let mut readings = Box::new(Vec::with_capacity(BUFFER_SIZE));
+            for (index, chunk) in queue.worker(cpu) {
+                let mut errors = ErrorReport::new(on_error);
+                let mut records = Records::new(&buffer[chunk.clone()], base_offset + chunk.start as u64)
+                    .strict(strict)
+                    .schema(schema);
+                for record in records.by_ref() {
+                    // Split the line at the semicolon, hash the name and parse the temperature
+                    let reading = record.and_then(|record| {
+                        Ok((hash_station_name(record.station.as_bytes()), record.value_fixed(scale)?))
+                    });
+                    match reading {
+                        Ok(reading) => readings.push(reading),
+                        Err(error) => {
+                            errors.record(error).map_err(|error| {
+                                queue.cancel();
+                                queue.locate(&buffer, index, error)
+                            })?;
+                            continue;
+                        }
+                    }
 
-                index = i + 1;
+                    if readings.len() == BUFFER_SIZE {
+                        my_tx.send(readings).await.unwrap();
+                        readings = Box::new(Vec::with_capacity(BUFFER_SIZE));
+                    }
+                }
+                reports.push(ChunkReport { index, errors, lines: records.lines() });
             }
-            // Send the remaining buffer
-            my_tx.send(buffer).await.unwrap();
+            // Send the remaining readings
+            my_tx.send(readings).await.unwrap();
+            Ok::<_, ParseError>(reports)
         });
         futures.push(future);
     }
 
-    // Spawn the receiver thread
-    std::mem::drop(tx); // Drop the sender to signal the receiver to finish
+    // Collect the bad lines; they're put back in file order once every task has finished.
+    use futures::future::join_all;
+    let mut errors = ChunkErrors::new(args.errors.on_error);
+    let results = join_all(futures).await;
+    stop_prefetching.store(true, Ordering::Relaxed);
+    for result in results {
+        match result? {
+            Ok(reports) => errors.add(reports),
+            Err(error) => errors.fail(error),
+        }
+    }
+    let lines = errors.lines();
+    let errors = errors.finish().map_err(|mut error| {
+        error.line += lines_before;
+        error
+    })?;
+    Ok((errors, lines))
+}
 
-    let _ = tokio::spawn(async move {
+async fn run(args: &Args, num_cpus: usize) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    let faults = PageFaults::now();
+
+    // Load the stations before starting anything, so that if we can't, there are no tasks
+    // left sending to a receiver that has gone
+    let mut stations = pre_hash_stations(&args.stats.accumulator())?;
+
+    // Build the channel
+    let (tx, mut rx) = mpsc::channel::<Readings>(4096);
+
+    // Spawn the receiver task. It lasts until every sender has gone, however many times the
+    // calculation tasks are started.
+    let receiver = tokio::spawn(async move {
         // Receive the results
-        let mut stations = pre_hash_stations().unwrap();
+        // Readings from stations we can't name are counted, by hash, so we can say so
+        let mut unknown: FxHashMap<u64, u64> = FxHashMap::default();
         while let Some(buffer) = rx.recv().await {
             for (hash, temperature) in buffer.iter() {
                 if let Some(station) = stations.get_mut(hash) {
-                    station.count += 1;
-                    station.min = station.min.min(*temperature);
-                    station.max = station.max.max(*temperature);
-                    station.sum += *temperature;
+                    station.readings.add(*temperature);
+                } else {
+                    *unknown.entry(*hash).or_default() += 1;
                 }
             }
         }
         //println!("Processed {} rows", counter);
+        (stations, unknown)
+    });
 
-        // Print the results
-        use std::io::Write;
-        let stdout = std::io::stdout();
-        let mut lock = stdout.lock();
-        for (_, station) in stations.iter().filter(|(_, station)| station.count > 0) {
-            let avg = station.sum as f32 / station.count as f32;
-            writeln!(&mut lock, "{};{};{};{}", station.name, station.min as f32 / 10.0, station.max as f32 / 10.0, avg).unwrap();
-        }
-
-        println!("Elapsed: {:.4} seconds", start.elapsed().as_secs_f32());
-    }).await;
-
-    use futures::future::join_all;
-    join_all(futures).await;
+    // The original sender is dropped when the input has been read, so the channel closes
+    let errors = read_input(args, args.source.path("../data_builder/measurements_1b.txt"), num_cpus, tx).await;
+    let (stations, unknown) = receiver.await?;
+    let errors = errors?;
 
+    // Print the results
+    use std::io::Write;
+    let stdout = std::io::stdout();
+    let mut lock = stdout.lock();
+    let scale = args.scale.scale;
+    let wanted = args.stats.percentiles();
+    let mut stations: Vec<&Station> = stations.values().filter(|station| station.readings.count > 0).collect();
+    args.rank.rank(&mut stations, |station| (&station.name, &station.readings));
+    for station in stations {
+        let readings = &station.readings;
+        let min = scale.format(readings.min as i64);
+        let max = scale.format(readings.max as i64);
+        write!(&mut lock, "{};{};{};{}", station.name, min, max, scale.format(readings.mean()))?;
+        if args.stats.stddev {
+            write!(&mut lock, ";{}", readings.spread(scale))?;
+        }
+        if let Some(percentiles) = readings.percentiles(&wanted, scale) {
+            write!(&mut lock, ";{percentiles}")?;
+        }
+        if let Some(ranked) = args.rank.measure(readings, scale) {
+            write!(&mut lock, ";{ranked}")?;
+        }
+        writeln!(&mut lock)?;
+    }
+    if errors.skipped() > 0 {
+        eprintln!("{errors}");
+    }
+    if !unknown.is_empty() {
+        let readings: u64 = unknown.values().sum();
+        eprintln!("Ignored {readings} reading(s) from {} station(s) that aren't in {STATIONS}", unknown.len());
+    }
 
+    println!("Elapsed: {:.4} seconds", start.elapsed().as_secs_f32());
+    println!("Page faults: {}", PageFaults::since(faults));
 
     Ok(())
 }